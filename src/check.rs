@@ -0,0 +1,330 @@
+use crate::context::*;
+use crate::controller;
+use crate::diagnostics_export::*;
+use crate::general;
+use crate::language_server_transport;
+use crate::types::*;
+use crossbeam_channel::{select, unbounded};
+use glob::glob;
+use jsonrpc_core::Call;
+use lsp_types::notification::{Notification, Progress};
+use lsp_types::*;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long the language server must stay quiet, with no work in progress, before its diagnostics
+/// are considered final.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Directories that are not searched for files to check, besides hidden ones.
+const IGNORED_DIRECTORIES: &[&str] = &["node_modules", "target"];
+
+/// Run the language server of the given language over a project without an editor and print the
+/// diagnostics it reports for every matching file.
+///
+/// Returns the exit status: 1 if there are errors, 2 if the check couldn't run, 0 otherwise.
+pub fn start(
+    config: Config,
+    language: &str,
+    root: &str,
+    globs: &[String],
+    format: Option<DiagnosticsExportFormat>,
+    timeout: Duration,
+) -> i32 {
+    let lang = match config.language.get(language) {
+        Some(lang) => lang.clone(),
+        None => {
+            eprintln!("kak-lsp: language {} is not configured", language);
+            return 2;
+        }
+    };
+    let filetype = match lang.filetypes.first() {
+        Some(filetype) => filetype.clone(),
+        None => {
+            eprintln!("kak-lsp: language {} has no filetypes", language);
+            return 2;
+        }
+    };
+    let globs = if globs.is_empty() {
+        filetype_globs(&lang.filetypes)
+    } else {
+        globs.to_vec()
+    };
+    if globs.is_empty() {
+        eprintln!(
+            "kak-lsp: don't know which files have filetypes {}, use --glob",
+            lang.filetypes.join(", ")
+        );
+        return 2;
+    }
+    let mut requests = project_files(root, &globs)
+        .into_iter()
+        .filter_map(|buffile| did_open_request(&buffile, &filetype));
+    let initial_request = match requests.next() {
+        Some(request) => request,
+        None => {
+            eprintln!("kak-lsp: no files matching {} in {}", globs.join(" "), root);
+            return 2;
+        }
+    };
+
+    let lang_srv = match language_server_transport::start(&lang.command, &lang.args) {
+        Ok(lang_srv) => lang_srv,
+        Err(err) => {
+            eprintln!("kak-lsp: failed to start language server: {}", err);
+            return 2;
+        }
+    };
+    let offset_encoding = lang.offset_encoding;
+    let meta = initial_request.meta.clone();
+    // Commands for the editor are dropped, there is none.
+    let (editor_tx, editor_rx) = unbounded();
+    let mut ctx = Context::new(
+        language,
+        initial_request,
+        lang_srv.to_lang_server.sender().clone(),
+        editor_tx,
+        config,
+        root.to_string(),
+        offset_encoding,
+    );
+    ctx.headless = true;
+    // All files are opened once the server is initialized.
+    ctx.pending_requests.extend(requests);
+    general::initialize(root, meta.clone(), &mut ctx);
+
+    let started = Instant::now();
+    let mut last_message = Instant::now();
+    let mut progress_tokens = HashSet::new();
+    // Whether the server stopped or timed out before its diagnostics were complete.
+    let mut incomplete = false;
+    loop {
+        select! {
+            recv(lang_srv.from_lang_server.receiver()) -> msg => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        eprintln!("kak-lsp: language server exited");
+                        incomplete = true;
+                        break;
+                    }
+                };
+                last_message = Instant::now();
+                track_progress(&msg, &mut progress_tokens);
+                controller::dispatch_server_message(msg, meta.clone(), &mut ctx);
+            }
+            recv(editor_rx) -> _ => (),
+            default(POLL_INTERVAL) => (),
+        }
+        if ctx.capabilities.is_some()
+            && ctx.pending_requests.is_empty()
+            && ctx.response_waitlist.is_empty()
+            && progress_tokens.is_empty()
+            && last_message.elapsed() >= SETTLE_TIME
+        {
+            break;
+        }
+        if started.elapsed() >= timeout {
+            eprintln!(
+                "kak-lsp: timed out after {}s waiting for diagnostics",
+                timeout.as_secs()
+            );
+            incomplete = true;
+            break;
+        }
+    }
+
+    let diagnostics = exported_diagnostics(&ctx);
+    general::exit(&mut ctx);
+    match format {
+        Some(format) => print!("{}", export_diagnostics(format, &diagnostics, &ctx)),
+        None => {
+            for d in &diagnostics {
+                println!(
+                    "{}:{}:{}: {}: {}",
+                    d.file,
+                    d.line,
+                    d.column,
+                    match d.severity {
+                        DiagnosticSeverity::Error => "error",
+                        DiagnosticSeverity::Warning => "warning",
+                        DiagnosticSeverity::Information => "info",
+                        DiagnosticSeverity::Hint => "hint",
+                    },
+                    d.message
+                );
+            }
+        }
+    }
+    if incomplete {
+        2
+    } else if diagnostics
+        .iter()
+        .any(|d| d.severity == DiagnosticSeverity::Error)
+    {
+        1
+    } else {
+        0
+    }
+}
+
+/// Keep track of the work the server reports as in progress.
+fn track_progress(msg: &ServerMessage, progress_tokens: &mut HashSet<String>) {
+    let params = match msg {
+        ServerMessage::Request(Call::Notification(notification))
+            if notification.method == Progress::METHOD =>
+        {
+            match notification.params.clone().parse::<ProgressParams>() {
+                Ok(params) => params,
+                Err(_) => return,
+            }
+        }
+        _ => return,
+    };
+    let token = match params.token {
+        NumberOrString::Number(number) => number.to_string(),
+        NumberOrString::String(string) => string,
+    };
+    match params.value {
+        ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(_)) => {
+            progress_tokens.insert(token);
+        }
+        ProgressParamsValue::WorkDone(WorkDoneProgress::End(_)) => {
+            progress_tokens.remove(&token);
+        }
+        ProgressParamsValue::WorkDone(WorkDoneProgress::Report(_)) => (),
+    }
+}
+
+fn did_open_request(buffile: &str, filetype: &str) -> Option<EditorRequest> {
+    let draft = match fs::read_to_string(buffile) {
+        Ok(draft) => draft,
+        Err(e) => {
+            warn!("Failed to read {}: {}", buffile, e);
+            return None;
+        }
+    };
+    let mut params = toml::value::Table::default();
+    params.insert("draft".to_string(), toml::Value::String(draft));
+    Some(EditorRequest {
+        meta: EditorMeta {
+            session: String::new(),
+            client: None,
+            buffile: buffile.to_string(),
+            filetype: filetype.to_string(),
+            version: 1,
+            fifo: None,
+        },
+        method: notification::DidOpenTextDocument::METHOD.to_string(),
+        params: toml::Value::Table(params),
+        ranges: None,
+    })
+}
+
+/// Files under the project root matching any of the globs, skipping hidden and build directories.
+fn project_files(root: &str, globs: &[String]) -> Vec<String> {
+    let mut files = globs
+        .iter()
+        .filter_map(|pattern| glob(&format!("{}/{}", root, pattern)).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            !relative
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .iter()
+                .filter_map(|component| component.to_str())
+                .any(|component| {
+                    component.starts_with('.') || IGNORED_DIRECTORIES.contains(&component)
+                })
+        })
+        .filter_map(|path| path.to_str().map(|path| path.to_string()))
+        .collect::<Vec<_>>();
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Globs for the files Kakoune gives one of the filetypes.
+fn filetype_globs(filetypes: &[String]) -> Vec<String> {
+    filetypes
+        .iter()
+        .flat_map(|filetype| {
+            let extensions: &[&str] = match filetype.as_str() {
+                "c" => &["c", "h"],
+                "cpp" => &["cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+                "crystal" => &["cr"],
+                "css" => &["css"],
+                "d" => &["d"],
+                "dart" => &["dart"],
+                "di" => &["di"],
+                "elixir" => &["ex", "exs"],
+                "elm" => &["elm"],
+                "go" => &["go"],
+                "haskell" => &["hs"],
+                "html" => &["html", "htm"],
+                "java" => &["java"],
+                "javascript" => &["js", "mjs", "jsx"],
+                "json" => &["json"],
+                "julia" => &["jl"],
+                "latex" => &["tex"],
+                "less" => &["less"],
+                "lua" => &["lua"],
+                "nim" => &["nim"],
+                "nix" => &["nix"],
+                "ocaml" => &["ml", "mli"],
+                "php" => &["php"],
+                "python" => &["py"],
+                "reason" => &["re", "rei"],
+                "ruby" => &["rb"],
+                "rust" => &["rs"],
+                "scss" => &["scss"],
+                "sh" => &["sh", "bash"],
+                "terraform" => &["tf"],
+                "typescript" => &["ts", "tsx"],
+                "yaml" => &["yaml", "yml"],
+                "zig" => &["zig"],
+                _ => &[],
+            };
+            extensions
+                .iter()
+                .map(|extension| format!("**/*.{}", extension))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_files_skips_hidden_and_build_directories() {
+        let root = std::env::temp_dir().join(format!("kak-lsp-check-{}", std::process::id()));
+        for dir in &["src/nested", ".git", "target/debug"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &[
+            "src/main.rs",
+            "src/nested/lib.rs",
+            ".git/x.rs",
+            "target/debug/out.rs",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let root_str = root.to_str().unwrap();
+        let files = project_files(root_str, &filetype_globs(&["rust".to_string()]));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                format!("{}/src/main.rs", root_str),
+                format!("{}/src/nested/lib.rs", root_str),
+            ]
+        );
+    }
+}
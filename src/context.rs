@@ -1,13 +1,16 @@
+use crate::language_features::pull_diagnostics::{DiagnosticOptions, PulledDiagnostics};
+use crate::snippet::ExpandedSnippet;
 use crate::types::*;
 use crossbeam_channel::Sender;
 use jsonrpc_core::{self, Call, Error, Failure, Id, Output, Success, Value, Version};
-use lsp_types::notification::Notification;
+use lsp_types::notification::{Cancel, Notification};
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs;
+use std::time::Instant;
 
 // Copy of Kakoune's timestamped buffer content.
 pub struct Document {
@@ -20,7 +23,29 @@ pub struct Document {
     pub text: ropey::Rope,
 }
 
+// The last completion list sent to the editor.
+#[derive(Default)]
+pub struct CompletionState {
+    // Completion items, in the same order as the candidates.
+    pub items: Vec<CompletionItem>,
+    // Buffer content the items were computed against.
+    pub text: ropey::Rope,
+    // Position the completion was requested at.
+    pub position: Position,
+    // Expanded snippets of the items that are snippets, as inserted by the candidates.
+    pub snippets: Vec<Option<ExpandedSnippet>>,
+    // Buffer, start of the completed token (as Kakoune column) and unfiltered items of the last
+    // complete list, which is filtered again as long as the user keeps typing that token.
+    pub buffile: String,
+    pub token_start: u32,
+    pub cached_items: Vec<CompletionItem>,
+    pub is_incomplete: bool,
+    // Index of the item whose resolve was requested last, the one the info box should show.
+    pub resolve_index: Option<usize>,
+}
+
 pub type ResponsesCallback = Box<dyn FnOnce(&mut Context, EditorMeta, Vec<Value>) -> ()>;
+pub type ErrorCallback = Box<dyn FnOnce(&mut Context, EditorMeta) -> ()>;
 type BatchNumber = usize;
 type BatchCount = BatchNumber;
 
@@ -28,10 +53,26 @@ pub struct Context {
     batch_counter: BatchNumber,
     pub batches:
         HashMap<BatchNumber, (BatchCount, Vec<serde_json::value::Value>, ResponsesCallback)>,
+    // Run instead of the callback of a batch when one of its requests fails.
+    pub batch_error_callbacks: HashMap<BatchNumber, ErrorCallback>,
     pub capabilities: Option<ServerCapabilities>,
+    pub completion: CompletionState,
     pub config: Config,
     pub diagnostics: HashMap<String, Vec<Diagnostic>>,
+    // Buffers with diagnostics updates not yet sent to the editor, and when they were scheduled.
+    pub diagnostics_to_publish: HashMap<String, Instant>,
+    // The last diagnostics sent to the editor for each buffer, with the buffer version.
+    pub published_diagnostics: HashMap<String, (i32, Vec<Diagnostic>)>,
+    // Support for pull diagnostics, which lsp-types doesn't include in the server capabilities.
+    pub diagnostic_provider: Option<DiagnosticOptions>,
+    pub pulled_diagnostics: HashMap<String, PulledDiagnostics>,
+    // The `textDocument/diagnostic` request still in flight for each buffer.
+    pub document_diagnostics_requests: HashMap<String, Id>,
+    // The `workspace/diagnostic` request still streaming, and the editor that asked for it.
+    pub workspace_diagnostics_request: Option<(Id, EditorMeta)>,
     pub editor_tx: Sender<EditorResponse>,
+    // Set when there is no editor to talk to, see `kak-lsp check`.
+    pub headless: bool,
     pub lang_srv_tx: Sender<ServerMessage>,
     pub language_id: String,
     pub pending_requests: Vec<EditorRequest>,
@@ -39,7 +80,14 @@ pub struct Context {
     pub response_waitlist: HashMap<Id, (EditorMeta, &'static str, BatchNumber)>,
     pub root_path: String,
     pub session: SessionId,
+    // The last signature help shown in the editor and its buffer. It is sent back to the server
+    // when signature help is requested again in that buffer, until insert mode is left.
+    pub signature_help: Option<SignatureHelp>,
+    pub signature_help_buffile: String,
     pub documents: HashMap<String, Document>,
+    // The editor blocked on a fifo until the edits to make before writing a buffer are computed,
+    // and when to give up waiting for the language server.
+    pub pre_save: Option<(EditorMeta, Instant)>,
     pub offset_encoding: OffsetEncoding,
 }
 
@@ -57,10 +105,19 @@ impl Context {
         Context {
             batch_counter: 0,
             batches: HashMap::default(),
+            batch_error_callbacks: HashMap::default(),
             capabilities: None,
+            completion: CompletionState::default(),
             config,
             diagnostics: HashMap::default(),
+            diagnostics_to_publish: HashMap::default(),
+            published_diagnostics: HashMap::default(),
+            diagnostic_provider: None,
+            pulled_diagnostics: HashMap::default(),
+            document_diagnostics_requests: HashMap::default(),
+            workspace_diagnostics_request: None,
             editor_tx,
+            headless: false,
             lang_srv_tx,
             language_id: language_id.to_string(),
             pending_requests: vec![initial_request],
@@ -68,11 +125,16 @@ impl Context {
             response_waitlist: HashMap::default(),
             root_path,
             session,
+            signature_help: None,
+            signature_help_buffile: String::new(),
             documents: HashMap::default(),
+            pre_save: None,
             offset_encoding,
         }
     }
 
+    /// Send a request to the language server and run the callback with its result. Returns the id
+    /// of the request.
     pub fn call<
         R: Request,
         F: for<'a> FnOnce(&'a mut Context, EditorMeta, R::Result) -> () + 'static,
@@ -81,7 +143,8 @@ impl Context {
         meta: EditorMeta,
         params: R::Params,
         callback: F,
-    ) where
+    ) -> Option<Id>
+    where
         R::Params: IntoParams,
         R::Result: for<'a> Deserialize<'a>,
     {
@@ -96,9 +159,12 @@ impl Context {
                     }
                 },
             ),
-        );
+        )
+        .pop()
     }
 
+    /// Send requests to the language server and run the callback with all their results. Returns
+    /// the ids of the requests.
     pub fn batch_call<
         R: Request,
         F: for<'a> FnOnce(&'a mut Context, EditorMeta, Vec<R::Result>) -> () + 'static,
@@ -107,7 +173,8 @@ impl Context {
         meta: EditorMeta,
         ops: Vec<R::Params>,
         callback: F,
-    ) where
+    ) -> Vec<Id>
+    where
         R::Params: IntoParams,
         R::Result: for<'a> Deserialize<'a>,
     {
@@ -126,15 +193,17 @@ impl Context {
                 }),
             ),
         );
+        let mut ids = Vec::with_capacity(ops.len());
         for params in ops {
             let params = params.into_params();
             if params.is_err() {
                 error!("Failed to convert params");
-                return;
+                return ids;
             }
             let id = self.next_request_id();
             self.response_waitlist
                 .insert(id.clone(), (meta.clone(), R::METHOD, batch_id));
+            ids.push(id.clone());
 
             let call = jsonrpc_core::MethodCall {
                 jsonrpc: Some(Version::V2),
@@ -150,6 +219,45 @@ impl Context {
                 error!("Failed to call language server");
             };
         }
+        ids
+    }
+
+    /// Like `batch_call`, but run `on_error` instead of the callback if any of the requests fails.
+    pub fn batch_call_or_else<
+        R: Request,
+        F: for<'a> FnOnce(&'a mut Context, EditorMeta, Vec<R::Result>) -> () + 'static,
+        E: for<'a> FnOnce(&'a mut Context, EditorMeta) -> () + 'static,
+    >(
+        &mut self,
+        meta: EditorMeta,
+        ops: Vec<R::Params>,
+        callback: F,
+        on_error: E,
+    ) -> Vec<Id>
+    where
+        R::Params: IntoParams,
+        R::Result: for<'a> Deserialize<'a>,
+    {
+        // `batch_call` takes the next batch number.
+        let batch_id = self.batch_counter;
+        let ids = self.batch_call::<R, _>(meta, ops, callback);
+        self.batch_error_callbacks
+            .insert(batch_id, Box::new(on_error));
+        ids
+    }
+
+    /// Cancel a request sent to the language server. Its response is ignored, if any.
+    pub fn cancel(&mut self, id: Id) {
+        if let Some((_, _, batch_id)) = self.response_waitlist.remove(&id) {
+            self.batches.remove(&batch_id);
+            self.batch_error_callbacks.remove(&batch_id);
+        }
+        let id = match id {
+            Id::Num(id) => NumberOrString::Number(id as i32),
+            Id::Str(id) => NumberOrString::String(id),
+            Id::Null => return,
+        };
+        self.notify::<Cancel>(CancelParams { id });
     }
 
     pub fn reply(&mut self, id: Id, result: Result<Value, Error>) {
@@ -254,3 +362,37 @@ impl Context {
         })
     }
 }
+
+/// A context for tests, with no language server and the editor commands sent to the receiver.
+#[cfg(test)]
+pub fn test_context(root_path: &str) -> (Context, crossbeam_channel::Receiver<EditorResponse>) {
+    let (lang_srv_tx, _) = crossbeam_channel::unbounded();
+    let (editor_tx, editor_rx) = crossbeam_channel::unbounded();
+    let ctx = Context::new(
+        "rust",
+        EditorRequest {
+            meta: test_meta(),
+            method: String::new(),
+            params: toml::Value::Table(Default::default()),
+            ranges: None,
+        },
+        lang_srv_tx,
+        editor_tx,
+        toml::from_str("[language]").unwrap(),
+        root_path.to_string(),
+        OffsetEncoding::Utf8,
+    );
+    (ctx, editor_rx)
+}
+
+#[cfg(test)]
+pub fn test_meta() -> EditorMeta {
+    EditorMeta {
+        session: String::new(),
+        client: None,
+        buffile: String::new(),
+        filetype: String::new(),
+        version: 0,
+        fifo: None,
+    }
+}
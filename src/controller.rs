@@ -2,6 +2,7 @@ use std::borrow::Cow;
 
 use crate::context::*;
 use crate::diagnostics;
+use crate::diagnostics_export;
 use crate::general;
 use crate::language_features::*;
 use crate::language_server_transport;
@@ -9,16 +10,19 @@ use crate::text_sync::*;
 use crate::types::*;
 use crate::util::*;
 use crate::workspace;
-use crossbeam_channel::{select, Receiver, Sender};
-use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params};
+use crossbeam_channel::{after, never, select, Receiver, Sender};
+use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params, Value};
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
+use std::time::Instant;
 
 // This is an error code defined by the language server protocol, signifying that a request was
 // cancelled because the content changed before it could be fulfilled. In this case, the user
 // should not be notified.
 const CONTENT_MODIFIED: i64 = -32801;
+// Sent by servers that cancel a pull diagnostics request, for example because of further edits.
+const SERVER_CANCELLED: i64 = -32802;
 
 /// Start controller.
 ///
@@ -81,6 +85,14 @@ pub fn start(
     general::initialize(&route.root, initial_request_meta.clone(), &mut ctx);
 
     'event_loop: loop {
+        let publish_diagnostics_timer = match diagnostics::publish_diagnostics_deadline(&ctx) {
+            Some(deadline) => after(deadline.saturating_duration_since(Instant::now())),
+            None => never(),
+        };
+        let pre_save_timer = match &ctx.pre_save {
+            Some((_, deadline)) => after(deadline.saturating_duration_since(Instant::now())),
+            None => never(),
+        };
         select! {
             recv(from_editor) -> msg => {
                 if msg.is_err() {
@@ -121,70 +133,85 @@ pub fn start(
                     break 'event_loop;
                 }
                 let msg = msg.unwrap();
-                match msg {
-                    ServerMessage::Request(call) => {
-                        match call {
-                            Call::MethodCall(request) => {
-                              dispatch_server_request(request, &mut ctx);
-                            }
-                            Call::Notification(notification) => {
-                                dispatch_server_notification(
-                                    initial_request_meta.clone(),
-                                    &notification.method,
-                                    notification.params,
-                                    &mut ctx,
-                                );
-                            }
-                            Call::Invalid {id} => {
-                                error!("Invalid call from language server: {:?}", id);
+                dispatch_server_message(msg, initial_request_meta.clone(), &mut ctx);
+            }
+            recv(publish_diagnostics_timer) -> _ => {
+                diagnostics::publish_scheduled_diagnostics(&mut ctx);
+            }
+            recv(pre_save_timer) -> _ => {
+                pre_save_timeout(&mut ctx);
+            }
+        }
+    }
+}
+
+/// Handle a message from the language server: dispatch its requests and notifications, and run
+/// the callbacks waiting for its responses.
+pub fn dispatch_server_message(msg: ServerMessage, meta: EditorMeta, ctx: &mut Context) {
+    match msg {
+        ServerMessage::Request(call) => match call {
+            Call::MethodCall(request) => {
+                dispatch_server_request(request, ctx);
+            }
+            Call::Notification(notification) => {
+                dispatch_server_notification(meta, &notification.method, notification.params, ctx);
+            }
+            Call::Invalid { id } => {
+                error!("Invalid call from language server: {:?}", id);
+            }
+        },
+        ServerMessage::Response(output) => {
+            match output {
+                Output::Success(success) => {
+                    if let Some((meta, _, batch_id)) = ctx.response_waitlist.remove(&success.id) {
+                        if let Some((batch_amt, mut vals, callback)) = ctx.batches.remove(&batch_id)
+                        {
+                            vals.push(success.result);
+                            if batch_amt == 1 {
+                                ctx.batch_error_callbacks.remove(&batch_id);
+                                callback(ctx, meta, vals);
+                            } else {
+                                ctx.batches
+                                    .insert(batch_id, (batch_amt - 1, vals, callback));
                             }
                         }
+                    } else {
+                        error!("Id {:?} is not in waitlist!", success.id);
                     }
-                    ServerMessage::Response(output) => {
-                        match output {
-                            Output::Success(success) => {
-                                if let Some((meta, _, batch_id)) = ctx.response_waitlist.remove(&success.id) {
-                                    if let Some((batch_amt, mut vals, callback)) = ctx.batches.remove(&batch_id) {
-                                        vals.push(success.result);
-                                        if batch_amt == 1 {
-                                            callback(&mut ctx, meta, vals);
-                                        } else {
-                                            ctx.batches.insert(batch_id, (batch_amt - 1, vals, callback));
-                                        }
-                                    }
-                                } else {
-                                    error!("Id {:?} is not in waitlist!", success.id);
-                                }
+                }
+                Output::Failure(failure) => {
+                    error!("Error response from server: {:?}", failure);
+                    if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
+                        let (meta, method, batch_id) = request;
+                        // The batch can't complete anymore.
+                        ctx.batches.remove(&batch_id);
+                        if let Some(on_error) = ctx.batch_error_callbacks.remove(&batch_id) {
+                            on_error(ctx, meta.clone());
+                        }
+                        match failure.error.code {
+                            ErrorCode::ServerError(CONTENT_MODIFIED)
+                            | ErrorCode::ServerError(SERVER_CANCELLED) => {
+                                // Nothing to do, but sending command back to the editor is required to handle case when
+                                // editor is blocked waiting for response via fifo.
+                                ctx.exec(meta, "nop".to_string());
                             }
-                            Output::Failure(failure) => {
-                                error!("Error response from server: {:?}", failure);
-                                if let Some(request) = ctx.response_waitlist.remove(&failure.id) {
-                                    let (meta, method, _) = request;
-                                    match failure.error.code {
-                                        ErrorCode::ServerError(CONTENT_MODIFIED) => {
-                                            // Nothing to do, but sending command back to the editor is required to handle case when
-                                            // editor is blocked waiting for response via fifo.
-                                            ctx.exec(meta, "nop".to_string());
-                                        },
-                                        code => {
-                                            let msg = match code {
-                                                ErrorCode::MethodNotFound => format!(
-                                                    "{} language server doesn't support method {}",
-                                                    ctx.language_id, method
-                                                ),
-                                                _ => format!(
-                                                    "{} language server error: {}",
-                                                    ctx.language_id, editor_quote(&failure.error.message)
-                                                ),
-                                            };
-                                            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
-                                        }
-                                    }
-                                } else {
-                                    error!("Id {:?} is not in waitlist!", failure.id);
-                                }
+                            code => {
+                                let msg = match code {
+                                    ErrorCode::MethodNotFound => format!(
+                                        "{} language server doesn't support method {}",
+                                        ctx.language_id, method
+                                    ),
+                                    _ => format!(
+                                        "{} language server error: {}",
+                                        ctx.language_id,
+                                        editor_quote(&failure.error.message)
+                                    ),
+                                };
+                                ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
                             }
                         }
+                    } else {
+                        error!("Id {:?} is not in waitlist!", failure.id);
                     }
                 }
             }
@@ -216,6 +243,12 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         notification::DidCloseTextDocument::METHOD => {
             text_document_did_close(meta, &mut ctx);
         }
+        notification::WillSaveTextDocument::METHOD => {
+            text_document_will_save(meta, ctx);
+        }
+        request::WillSaveWaitUntil::METHOD => {
+            text_document_will_save_wait_until(meta, ctx);
+        }
         notification::DidSaveTextDocument::METHOD => {
             text_document_did_save(meta, &mut ctx);
         }
@@ -225,6 +258,12 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         request::Completion::METHOD => {
             completion::text_document_completion(meta, params, &mut ctx);
         }
+        request::ResolveCompletionItem::METHOD => {
+            completion::completion_item_resolve(meta, params, ctx);
+        }
+        "completion-item-accept" => {
+            completion::completion_item_accept(meta, params, ctx);
+        }
         request::CodeActionRequest::METHOD => {
             codeaction::text_document_codeaction(meta, params, &mut ctx);
         }
@@ -252,6 +291,12 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         request::SignatureHelpRequest::METHOD => {
             signature_help::text_document_signature_help(meta, params, &mut ctx);
         }
+        "signature-help-cycle" => {
+            signature_help::signature_help_cycle(meta, params, ctx);
+        }
+        "signature-help-end" => {
+            signature_help::signature_help_end(ctx);
+        }
         request::DocumentHighlightRequest::METHOD => {
             highlights::text_document_highlights(meta, params, &mut ctx);
         }
@@ -276,12 +321,21 @@ fn dispatch_editor_request(request: EditorRequest, mut ctx: &mut Context) {
         "textDocument/diagnostics" => {
             diagnostics::editor_diagnostics(meta, &mut ctx);
         }
+        "export-diagnostics" => {
+            diagnostics_export::editor_export_diagnostics(meta, params, ctx);
+        }
         "capabilities" => {
             general::capabilities(meta, &mut ctx);
         }
         "apply-workspace-edit" => {
             workspace::apply_edit_from_editor(meta, params, ctx);
         }
+        "apply-code-action" => {
+            codeaction::apply_code_action_from_editor(meta, params, ctx);
+        }
+        "code-actions-on-save" => {
+            codeaction::text_document_code_actions_on_save(meta, params, ctx);
+        }
         request::SemanticTokensFullRequest::METHOD => {
             semantic_tokens::tokens_request(meta, params, ctx);
         }
@@ -331,6 +385,10 @@ fn dispatch_server_request(request: MethodCall, ctx: &mut Context) {
             workspace::apply_edit_from_server(request.params, ctx)
         }
         request::WorkspaceConfiguration::METHOD => workspace::configuration(request.params, ctx),
+        request::WorkDoneProgressCreate::METHOD => Ok(Value::Null),
+        pull_diagnostics::WORKSPACE_DIAGNOSTIC_REFRESH => {
+            pull_diagnostics::workspace_diagnostic_refresh(ctx)
+        }
         _ => {
             warn!("Unsupported method: {}", method);
             Err(jsonrpc_core::Error::new(
@@ -400,6 +458,9 @@ fn dispatch_server_notification(
                 ),
             );
         }
+        "$/progress" => {
+            pull_diagnostics::progress(params, ctx);
+        }
         "telemetry/event" => {
             debug!("{:?}", params);
         }
@@ -423,7 +484,10 @@ fn dispatch_server_notification(
 /// most of the time in `if buffile.is_empty() || ctx.documents.contains_key(buffile)` condition.
 fn ensure_did_open(request: &EditorRequest, mut ctx: &mut Context) {
     let buffile = &request.meta.buffile;
-    if buffile.is_empty() || ctx.documents.contains_key(buffile) {
+    if buffile.is_empty()
+        || ctx.documents.contains_key(buffile)
+        || request.method == notification::DidOpenTextDocument::METHOD
+    {
         return;
     };
     if request.method == notification::DidChangeTextDocument::METHOD {
@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::language_features::pull_diagnostics;
 use crate::position::*;
 use crate::types::*;
 use crate::util::*;
@@ -6,35 +7,94 @@ use itertools::Itertools;
 use jsonrpc_core::Params;
 use lsp_types::*;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long diagnostics updates of a buffer are collected before sending the latest to the editor.
+const PUBLISH_DIAGNOSTICS_DELAY: Duration = Duration::from_millis(100);
 
 pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
     let params: PublishDiagnosticsParams = params.parse().expect("Failed to parse params");
-    let session = ctx.session.clone();
-    let client = None;
     let path = params.uri.to_file_path().unwrap();
     let buffile = path.to_str().unwrap();
-    ctx.diagnostics
-        .insert(buffile.to_string(), params.diagnostics);
-    let document = ctx.documents.get(buffile);
-    if document.is_none() {
-        return;
+    update_diagnostics(buffile, params.diagnostics, ctx);
+}
+
+/// Store the diagnostics of a file and schedule showing them in its buffer if it is open in the
+/// editor. Servers can send many updates in a row, so the updates of a buffer that arrive within
+/// `PUBLISH_DIAGNOSTICS_DELAY` are coalesced and only the latest is sent.
+pub fn update_diagnostics(buffile: &str, diagnostics: Vec<Diagnostic>, ctx: &mut Context) {
+    let diagnostics = match ctx.config.language.get(&ctx.language_id) {
+        Some(language) => apply_diagnostic_rules(diagnostics, &language.diagnostic_rules),
+        None => diagnostics,
+    };
+    ctx.diagnostics.insert(buffile.to_string(), diagnostics);
+    if ctx.documents.contains_key(buffile) {
+        ctx.diagnostics_to_publish
+            .entry(buffile.to_string())
+            .or_insert_with(Instant::now);
+    }
+}
+
+/// When the next scheduled diagnostics update is due.
+pub fn publish_diagnostics_deadline(ctx: &Context) -> Option<Instant> {
+    ctx.diagnostics_to_publish
+        .values()
+        .min()
+        .map(|scheduled| *scheduled + PUBLISH_DIAGNOSTICS_DELAY)
+}
+
+/// Send the scheduled diagnostics updates that are due to the editor.
+pub fn publish_scheduled_diagnostics(ctx: &mut Context) {
+    let now = Instant::now();
+    let due = ctx
+        .diagnostics_to_publish
+        .iter()
+        .filter(|(_, scheduled)| **scheduled + PUBLISH_DIAGNOSTICS_DELAY <= now)
+        .map(|(buffile, _)| buffile.clone())
+        .collect::<Vec<_>>();
+    for buffile in due {
+        ctx.diagnostics_to_publish.remove(&buffile);
+        editor_publish_diagnostics(&buffile, ctx);
     }
-    let document = document.unwrap();
+}
+
+fn editor_publish_diagnostics(buffile: &str, ctx: &mut Context) {
+    let session = ctx.session.clone();
+    let client = None;
+    let document = match ctx.documents.get(buffile) {
+        Some(document) => document,
+        None => return,
+    };
     let version = document.version;
-    let diagnostics = &ctx.diagnostics[buffile];
+    let diagnostics = match ctx.diagnostics.get(buffile) {
+        Some(diagnostics) => diagnostics,
+        None => return,
+    };
+    // Skip updates that wouldn't change what the editor shows.
+    if let Some((published_version, published)) = ctx.published_diagnostics.get(buffile) {
+        if *published_version == version && published == diagnostics {
+            return;
+        }
+    }
     let ranges = diagnostics
         .iter()
-        .map(|x| {
-            format!(
-                "{}|{}",
-                lsp_range_to_kakoune(&x.range, &document.text, ctx.offset_encoding),
-                match x.severity {
-                    Some(DiagnosticSeverity::Error) => "DiagnosticError",
-                    Some(DiagnosticSeverity::Hint) => "DiagnosticHint",
-                    Some(DiagnosticSeverity::Information) => "DiagnosticInfo",
-                    Some(DiagnosticSeverity::Warning) | None => "DiagnosticWarning",
-                }
-            )
+        .flat_map(|x| {
+            let range = lsp_range_to_kakoune(&x.range, &document.text, ctx.offset_encoding);
+            let severity_face = match x.severity {
+                Some(DiagnosticSeverity::Error) => "DiagnosticError",
+                Some(DiagnosticSeverity::Hint) => "DiagnosticHint",
+                Some(DiagnosticSeverity::Information) => "DiagnosticInfo",
+                Some(DiagnosticSeverity::Warning) | None => "DiagnosticWarning",
+            };
+            // Tagged code gets an extra range, so it is shown faded or struck through on top of
+            // the severity face.
+            let tag_faces = x.tags.iter().flatten().map(|tag| match tag {
+                DiagnosticTag::Unnecessary => "DiagnosticTagUnnecessary",
+                DiagnosticTag::Deprecated => "DiagnosticTagDeprecated",
+            });
+            std::iter::once(severity_face)
+                .chain(tag_faces)
+                .map(move |face| format!("{}|{}", range, face))
         })
         .join(" ");
 
@@ -167,10 +227,61 @@ pub fn publish_diagnostics(params: Params, ctx: &mut Context) {
         version,
         fifo: None,
     };
+    let published = (version, diagnostics.clone());
     ctx.exec(meta, command);
+    ctx.published_diagnostics
+        .insert(buffile.to_string(), published);
+}
+
+/// Apply the first matching rule to each diagnostic, changing its severity or dropping it.
+fn apply_diagnostic_rules(
+    diagnostics: Vec<Diagnostic>,
+    rules: &[DiagnosticRule],
+) -> Vec<Diagnostic> {
+    if rules.is_empty() {
+        return diagnostics;
+    }
+    diagnostics
+        .into_iter()
+        .filter_map(|mut diagnostic| {
+            let rule = rules
+                .iter()
+                .find(|rule| diagnostic_rule_matches(rule, &diagnostic));
+            diagnostic.severity = match rule.map(|rule| rule.severity) {
+                None => diagnostic.severity,
+                Some(DiagnosticRuleSeverity::Error) => Some(DiagnosticSeverity::Error),
+                Some(DiagnosticRuleSeverity::Warning) => Some(DiagnosticSeverity::Warning),
+                Some(DiagnosticRuleSeverity::Info) => Some(DiagnosticSeverity::Information),
+                Some(DiagnosticRuleSeverity::Hint) => Some(DiagnosticSeverity::Hint),
+                Some(DiagnosticRuleSeverity::Hide) => return None,
+            };
+            Some(diagnostic)
+        })
+        .collect()
+}
+
+fn diagnostic_rule_matches(rule: &DiagnosticRule, diagnostic: &Diagnostic) -> bool {
+    let code = diagnostic.code.as_ref().map(|code| match code {
+        NumberOrString::Number(number) => number.to_string(),
+        NumberOrString::String(string) => string.clone(),
+    });
+    (rule.source.is_none() || rule.source == diagnostic.source)
+        && (rule.code.is_none() || rule.code == code)
+        && match &rule.message {
+            Some(regex) => regex.is_match(&diagnostic.message),
+            None => true,
+        }
 }
 
 pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
+    // Show the diagnostics we already know about right away. If the server supports it, the
+    // diagnostics of the whole project are requested too, and shown as they arrive.
+    pull_diagnostics::workspace_diagnostic(meta.clone(), ctx);
+    show_diagnostics(meta, ctx);
+}
+
+/// Render all known diagnostics in the project diagnostics buffer.
+pub fn show_diagnostics(meta: EditorMeta, ctx: &mut Context) {
     let content = ctx
         .diagnostics
         .iter()
@@ -179,7 +290,7 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
                 .iter()
                 .map(|x| {
                     let p = get_kakoune_position(filename, &x.range.start, ctx).unwrap();
-                    format!(
+                    let mut entry = format!(
                         "{}:{}:{}: {}:{}",
                         short_file_path(filename, &ctx.root_path),
                         p.line,
@@ -191,7 +302,15 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
                             Some(DiagnosticSeverity::Warning) | None => "warning",
                         },
                         x.message
-                    )
+                    );
+                    if let Some(code_description) = &x.code_description {
+                        entry.push_str(&format!(" <{}>", code_description.href));
+                    }
+                    for location in related_information_locations(x, ctx) {
+                        entry.push_str("\n  ");
+                        entry.push_str(&location);
+                    }
+                    entry
                 })
                 .collect::<Vec<_>>()
         })
@@ -203,3 +322,77 @@ pub fn editor_diagnostics(meta: EditorMeta, ctx: &mut Context) {
     );
     ctx.exec(meta, command);
 }
+
+/// Render the related information of a diagnostic as `file:line:col: message` entries, which can
+/// be jumped to from a `lsp-goto` buffer.
+pub fn related_information_locations(diagnostic: &Diagnostic, ctx: &Context) -> Vec<String> {
+    diagnostic
+        .related_information
+        .iter()
+        .flatten()
+        .filter_map(|info| {
+            let path = info.location.uri.to_file_path().ok()?;
+            let filename = path.to_str()?;
+            let p = get_kakoune_position(filename, &info.location.range.start, ctx)?;
+            Some(format!(
+                "{}:{}:{}: {}",
+                short_file_path(filename, &ctx.root_path),
+                p.line,
+                p.column,
+                info.message
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_diagnostic_rules_remaps_and_hides() {
+        let diagnostic = |source: &str, code: &str, message: &str| Diagnostic {
+            source: Some(source.to_string()),
+            code: Some(NumberOrString::String(code.to_string())),
+            message: message.to_string(),
+            severity: Some(DiagnosticSeverity::Warning),
+            ..Diagnostic::default()
+        };
+        let rules: Vec<DiagnosticRule> = toml::from_str::<HashMap<String, Vec<DiagnosticRule>>>(
+            r#"
+            rules = [
+                { code = "clippy::needless_return", severity = "hint" },
+                { source = "pylint", severity = "hide" },
+                { message = "^unused", severity = "info" },
+            ]
+            "#,
+        )
+        .unwrap()
+        .remove("rules")
+        .unwrap();
+        let diagnostics = apply_diagnostic_rules(
+            vec![
+                diagnostic("clippy", "clippy::needless_return", "unneeded `return`"),
+                diagnostic("pylint", "C0114", "missing module docstring"),
+                diagnostic("rustc", "unused_variables", "unused variable: `x`"),
+                diagnostic("rustc", "E0308", "mismatched types"),
+            ],
+            &rules,
+        );
+        let severities = diagnostics
+            .iter()
+            .map(|d| (d.message.as_str(), d.severity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            severities,
+            vec![
+                ("unneeded `return`", Some(DiagnosticSeverity::Hint)),
+                (
+                    "unused variable: `x`",
+                    Some(DiagnosticSeverity::Information)
+                ),
+                ("mismatched types", Some(DiagnosticSeverity::Warning)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use crate::context::*;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::*;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use url::Url;
+
+/// A diagnostic with its location resolved to Kakoune coordinates, ready to be written out.
+#[derive(Debug, PartialEq)]
+pub struct ExportedDiagnostic {
+    // Path relative to the project root.
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    // Position just after the end of the diagnostic range.
+    pub end_line: u32,
+    pub end_column: u32,
+    pub severity: DiagnosticSeverity,
+    pub source: Option<String>,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+/// Write the diagnostics of the project in the requested format, either to a file or to a scratch
+/// buffer.
+pub fn editor_export_diagnostics(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    // The format is typed by the user.
+    let params = match DiagnosticsExportParams::deserialize(params) {
+        Ok(params) => params,
+        Err(e) => {
+            let msg = format!("invalid diagnostics export: {}", e);
+            ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+            return;
+        }
+    };
+    let diagnostics = exported_diagnostics(ctx);
+    let content = export_diagnostics(params.format, &diagnostics, ctx);
+    let command = match params.output {
+        Some(output) => match fs::write(&output, content) {
+            Ok(()) => format!(
+                "echo {}",
+                editor_quote(&format!(
+                    "kak-lsp: exported {} diagnostics to {}",
+                    diagnostics.len(),
+                    output
+                ))
+            ),
+            Err(e) => format!(
+                "lsp-show-error {}",
+                editor_quote(&format!("failed to write {}: {}", output, e))
+            ),
+        },
+        None => format!("lsp-show-diagnostics-export {}", editor_quote(&content)),
+    };
+    ctx.exec(meta, command);
+}
+
+/// Collect all known diagnostics, sorted by file and position so that the output is stable.
+pub fn exported_diagnostics(ctx: &Context) -> Vec<ExportedDiagnostic> {
+    ctx.diagnostics
+        .iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .flat_map(|(filename, diagnostics)| {
+            let text = get_file_contents(filename, ctx);
+            let position = move |position: &Position| match &text {
+                Some(text) => lsp_position_to_kakoune(position, text, ctx.offset_encoding),
+                None => KakounePosition {
+                    line: position.line + 1,
+                    column: position.character + 1,
+                },
+            };
+            diagnostics
+                .iter()
+                .map(|diagnostic| {
+                    let start = position(&diagnostic.range.start);
+                    let end = position(&diagnostic.range.end);
+                    ExportedDiagnostic {
+                        file: short_file_path(filename, &ctx.root_path).to_string(),
+                        line: start.line,
+                        column: start.column,
+                        end_line: end.line,
+                        end_column: end.column,
+                        severity: diagnostic.severity.unwrap_or(DiagnosticSeverity::Warning),
+                        source: diagnostic.source.clone(),
+                        code: diagnostic.code.as_ref().map(|code| match code {
+                            NumberOrString::Number(number) => number.to_string(),
+                            NumberOrString::String(string) => string.clone(),
+                        }),
+                        message: diagnostic.message.clone(),
+                    }
+                })
+                .sorted_by_key(|d| (d.line, d.column))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+pub fn export_diagnostics(
+    format: DiagnosticsExportFormat,
+    diagnostics: &[ExportedDiagnostic],
+    ctx: &Context,
+) -> String {
+    match format {
+        DiagnosticsExportFormat::Json => json_lines(diagnostics),
+        DiagnosticsExportFormat::Sarif => {
+            let tool = ctx
+                .config
+                .language
+                .get(&ctx.language_id)
+                .map(|language| language.command.as_str())
+                .unwrap_or("kak-lsp");
+            sarif(diagnostics, tool, &ctx.root_path)
+        }
+        DiagnosticsExportFormat::Checkstyle => checkstyle(diagnostics),
+    }
+}
+
+fn severity_name(severity: DiagnosticSeverity) -> &'static str {
+    match severity {
+        DiagnosticSeverity::Error => "error",
+        DiagnosticSeverity::Warning => "warning",
+        DiagnosticSeverity::Information => "info",
+        DiagnosticSeverity::Hint => "hint",
+    }
+}
+
+/// One JSON object per line.
+fn json_lines(diagnostics: &[ExportedDiagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| {
+            json!({
+                "file": d.file,
+                "line": d.line,
+                "column": d.column,
+                "end_line": d.end_line,
+                "end_column": d.end_column,
+                "severity": severity_name(d.severity),
+                "source": d.source,
+                "code": d.code,
+                "message": d.message,
+            })
+            .to_string()
+        })
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// A SARIF 2.1.0 log with a single run, with file locations relative to the project root.
+fn sarif(diagnostics: &[ExportedDiagnostic], tool: &str, root_path: &str) -> String {
+    let results = diagnostics
+        .iter()
+        .map(|d| {
+            let mut result = json!({
+                "level": match d.severity {
+                    DiagnosticSeverity::Error => "error",
+                    DiagnosticSeverity::Warning => "warning",
+                    DiagnosticSeverity::Information | DiagnosticSeverity::Hint => "note",
+                },
+                "message": { "text": d.message },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": d.file, "uriBaseId": "SRCROOT" },
+                        "region": {
+                            "startLine": d.line,
+                            "startColumn": d.column,
+                            "endLine": d.end_line,
+                            "endColumn": d.end_column,
+                        },
+                    },
+                }],
+            });
+            if let Some(code) = &d.code {
+                result["ruleId"] = Value::from(code.as_str());
+            }
+            result
+        })
+        .collect::<Vec<_>>();
+    let root_uri = Url::from_directory_path(root_path)
+        .map(|uri| uri.to_string())
+        .unwrap_or_default();
+    let log = json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": { "driver": { "name": tool } },
+            "originalUriBaseIds": { "SRCROOT": { "uri": root_uri } },
+            "results": results,
+        }],
+    });
+    serde_json::to_string_pretty(&log).unwrap() + "\n"
+}
+
+/// Checkstyle XML, grouping diagnostics by file.
+fn checkstyle(diagnostics: &[ExportedDiagnostic]) -> String {
+    let mut xml =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<checkstyle version=\"4.3\">\n");
+    for (file, diagnostics) in &diagnostics.iter().group_by(|d| &d.file) {
+        xml.push_str(&format!("  <file name=\"{}\">\n", escape_xml(file)));
+        for d in diagnostics {
+            let source = match (&d.source, &d.code) {
+                (Some(source), Some(code)) => format!("{}.{}", source, code),
+                (Some(name), None) | (None, Some(name)) => name.clone(),
+                (None, None) => String::new(),
+            };
+            xml.push_str(&format!(
+                "    <error line=\"{}\" column=\"{}\" severity=\"{}\" message=\"{}\" source=\"{}\"/>\n",
+                d.line,
+                d.column,
+                match d.severity {
+                    DiagnosticSeverity::Error => "error",
+                    DiagnosticSeverity::Warning => "warning",
+                    DiagnosticSeverity::Information | DiagnosticSeverity::Hint => "info",
+                },
+                escape_xml(&d.message),
+                escape_xml(&source)
+            ));
+        }
+        xml.push_str("  </file>\n");
+    }
+    xml.push_str("</checkstyle>\n");
+    xml
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(file: &str, line: u32, message: &str) -> ExportedDiagnostic {
+        ExportedDiagnostic {
+            file: file.to_string(),
+            line,
+            column: 5,
+            end_line: line,
+            end_column: 9,
+            severity: DiagnosticSeverity::Error,
+            source: Some("rustc".to_string()),
+            code: Some("E0308".to_string()),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn checkstyle_groups_by_file_and_escapes() {
+        let diagnostics = vec![
+            diagnostic("src/a.rs", 1, "expected `&str`, found \"x\""),
+            diagnostic("src/a.rs", 3, "a < b"),
+            diagnostic("src/b.rs", 2, "oops"),
+        ];
+        assert_eq!(
+            checkstyle(&diagnostics),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<checkstyle version="4.3">
+  <file name="src/a.rs">
+    <error line="1" column="5" severity="error" message="expected `&amp;str`, found &quot;x&quot;" source="rustc.E0308"/>
+    <error line="3" column="5" severity="error" message="a &lt; b" source="rustc.E0308"/>
+  </file>
+  <file name="src/b.rs">
+    <error line="2" column="5" severity="error" message="oops" source="rustc.E0308"/>
+  </file>
+</checkstyle>
+"#
+        );
+    }
+
+    #[test]
+    fn unknown_format_is_reported() {
+        let (mut ctx, editor_rx) = test_context("/");
+        let params = toml::from_str("format = \"xml\"").unwrap();
+        editor_export_diagnostics(test_meta(), params, &mut ctx);
+        let response = editor_rx.try_recv().unwrap();
+        assert!(response
+            .command
+            .starts_with("lsp-show-error 'invalid diagnostics export: unknown variant `xml`"));
+    }
+
+    #[test]
+    fn json_lines_has_one_object_per_diagnostic() {
+        let output = json_lines(&[diagnostic("src/a.rs", 1, "x"), diagnostic("b.rs", 2, "y")]);
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let first: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["file"], "src/a.rs");
+        assert_eq!(first["severity"], "error");
+        assert_eq!(first["code"], "E0308");
+    }
+}
@@ -1,5 +1,6 @@
 use crate::context::*;
 use crate::controller;
+use crate::language_features::pull_diagnostics;
 use crate::settings::request_initialization_options_from_kakoune;
 use crate::types::*;
 use crate::util::*;
@@ -7,10 +8,21 @@ use itertools::Itertools;
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
+use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashSet;
 use std::process;
 use url::Url;
 
+/// The initialize request, with capabilities that lsp-types doesn't know about yet.
+enum InitializeWithExtensions {}
+
+impl Request for InitializeWithExtensions {
+    type Params = Value;
+    type Result = Value;
+    const METHOD: &'static str = Initialize::METHOD;
+}
+
 pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
     let initialization_options = request_initialization_options_from_kakoune(&meta, ctx);
     #[allow(deprecated)] // for root_path
@@ -25,7 +37,7 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                         ResourceOperationKind::Delete,
                         ResourceOperationKind::Rename,
                     ]),
-                    failure_handling: Some(FailureHandlingKind::Abort),
+                    failure_handling: Some(FailureHandlingKind::Transactional),
                     normalizes_line_endings: Some(false),
                     change_annotation_support: Some(
                         ChangeAnnotationWorkspaceEditClientCapabilities {
@@ -83,8 +95,8 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
             text_document: Some(TextDocumentClientCapabilities {
                 synchronization: Some(TextDocumentSyncClientCapabilities {
                     dynamic_registration: Some(false),
-                    will_save: Some(false),
-                    will_save_wait_until: Some(false),
+                    will_save: Some(true),
+                    will_save_wait_until: Some(true),
                     did_save: Some(true),
                 }),
                 completion: Some(CompletionClientCapabilities {
@@ -99,8 +111,14 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                         deprecated_support: Some(false),
                         preselect_support: Some(false),
                         tag_support: None,
-                        insert_replace_support: None,
-                        resolve_support: None,
+                        insert_replace_support: Some(true),
+                        resolve_support: Some(CompletionItemCapabilityResolveSupport {
+                            properties: vec![
+                                "detail".to_string(),
+                                "documentation".to_string(),
+                                "additionalTextEdits".to_string(),
+                            ],
+                        }),
                         insert_text_mode_support: None,
                         label_details_support: None,
                     }),
@@ -133,7 +151,7 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                             CompletionItemKind::TypeParameter,
                         ]),
                     }),
-                    context_support: Some(false),
+                    context_support: Some(true),
                     insert_text_mode: None,
                 }),
                 hover: Some(HoverClientCapabilities {
@@ -143,13 +161,16 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                 signature_help: Some(SignatureHelpClientCapabilities {
                     dynamic_registration: Some(false),
                     signature_information: Some(SignatureInformationSettings {
-                        documentation_format: Some(vec![MarkupKind::PlainText]),
+                        documentation_format: Some(vec![
+                            MarkupKind::Markdown,
+                            MarkupKind::PlainText,
+                        ]),
                         parameter_information: Some(ParameterInformationSettings {
-                            label_offset_support: Some(false),
+                            label_offset_support: Some(true),
                         }),
-                        active_parameter_support: None,
+                        active_parameter_support: Some(true),
                     }),
-                    context_support: Some(false),
+                    context_support: Some(true),
                 }),
                 references: Some(DynamicRegistrationClientCapabilities {
                     dynamic_registration: Some(false),
@@ -206,10 +227,12 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                             .collect(),
                         },
                     }),
-                    is_preferred_support: Some(false),
-                    disabled_support: None,
-                    data_support: None,
-                    resolve_support: None,
+                    is_preferred_support: Some(true),
+                    disabled_support: Some(true),
+                    data_support: Some(true),
+                    resolve_support: Some(CodeActionCapabilityResolveSupport {
+                        properties: vec!["edit".to_string()],
+                    }),
                     honors_change_annotations: None,
                 }),
                 code_lens: Some(DynamicRegistrationClientCapabilities {
@@ -229,10 +252,12 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                     honors_change_annotations: None,
                 }),
                 publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
-                    related_information: Some(false),
-                    tag_support: None,
+                    related_information: Some(true),
+                    tag_support: Some(TagSupport {
+                        value_set: vec![DiagnosticTag::Unnecessary, DiagnosticTag::Deprecated],
+                    }),
                     version_support: None,
-                    code_description_support: None,
+                    code_description_support: Some(true),
                     data_support: None,
                 }),
                 folding_range: None,
@@ -271,7 +296,8 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
                 moniker: None,
             }),
             window: Some(WindowClientCapabilities {
-                work_done_progress: Some(false),
+                // Only a headless check waits for the server to finish its work.
+                work_done_progress: Some(ctx.headless),
                 show_message: None,
                 show_document: None,
             }),
@@ -302,11 +328,31 @@ pub fn initialize(root_path: &str, meta: EditorMeta, ctx: &mut Context) {
         locale: None,
     };
 
-    ctx.call::<Initialize, _>(meta, params, move |ctx: &mut Context, _meta, result| {
-        ctx.capabilities = Some(result.capabilities);
-        ctx.notify::<Initialized>(InitializedParams {});
-        controller::dispatch_pending_editor_requests(ctx)
-    });
+    let mut params = serde_json::to_value(params).unwrap();
+    pull_diagnostics::add_client_capabilities(&mut params["capabilities"]);
+
+    ctx.call::<InitializeWithExtensions, _>(
+        meta,
+        params,
+        move |ctx: &mut Context, meta, result| {
+            ctx.diagnostic_provider = pull_diagnostics::server_capabilities(&result);
+            let result = match InitializeResult::deserialize(result) {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Failed to parse initialize result: {}", e);
+                    let msg = format!(
+                        "{} language server sent an invalid initialize result: {}",
+                        ctx.language_id, e
+                    );
+                    ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+                    return;
+                }
+            };
+            ctx.capabilities = Some(result.capabilities);
+            ctx.notify::<Initialized>(InitializedParams {});
+            controller::dispatch_pending_editor_requests(ctx)
+        },
+    );
 }
 
 pub fn exit(ctx: &mut Context) {
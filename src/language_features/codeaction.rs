@@ -1,47 +1,127 @@
 use crate::context::*;
+use crate::position::*;
+use crate::text_edit::apply_text_edits_to_buffer;
+use crate::text_sync::{begin_pre_save, finish_pre_save};
 use crate::types::*;
 use crate::util::*;
+use crate::workspace;
 use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
+use serde_json::Value;
 use url::Url;
 
 pub fn text_document_codeaction(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
-    let params =
-        PositionParams::deserialize(params).expect("Params should follow PositionParams structure");
-    let position = get_lsp_position(&meta.buffile, &params.position, ctx).unwrap();
+    let params = CodeActionsParams::deserialize(params)
+        .expect("Params should follow CodeActionsParams structure");
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => return,
+    };
+    let ranges = params
+        .selections_desc
+        .iter()
+        .filter_map(|desc| desc.parse::<KakouneRange>().ok())
+        .map(|selection| kakoune_selection_to_lsp(&selection, &document.text, ctx.offset_encoding))
+        .collect::<Vec<_>>();
+    let only = if params.only.is_empty() {
+        None
+    } else {
+        Some(params.only.into_iter().map(CodeActionKind::from).collect())
+    };
 
     let buff_diags = ctx.diagnostics.get(&meta.buffile);
-
-    let diagnostics: Vec<Diagnostic> = if let Some(buff_diags) = buff_diags {
-        buff_diags
+    let cursor_diagnostics: Vec<Diagnostic> = match (buff_diags, ranges.first()) {
+        (Some(buff_diags), Some(range)) => buff_diags
             .iter()
-            .filter(|d| d.range.start.line <= position.line && position.line <= d.range.end.line)
+            .filter(|d| d.range.start <= range.start && range.start <= d.range.end)
             .cloned()
-            .collect()
-    } else {
-        Vec::new()
+            .collect(),
+        _ => Vec::new(),
     };
-
-    let req_params = CodeActionParams {
-        text_document: TextDocumentIdentifier {
-            uri: Url::from_file_path(&meta.buffile).unwrap(),
-        },
-        range: Range {
-            start: position,
-            end: position,
-        },
-        context: CodeActionContext {
-            diagnostics,
-            only: None,
+    let auto_single = params.auto_single;
+    let req_params = ranges
+        .into_iter()
+        .map(|range| {
+            let diagnostics: Vec<Diagnostic> = if let Some(buff_diags) = buff_diags {
+                buff_diags
+                    .iter()
+                    .filter(|d| {
+                        d.range.start.line <= range.end.line && range.start.line <= d.range.end.line
+                    })
+                    .cloned()
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            CodeActionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(&meta.buffile).unwrap(),
+                },
+                range,
+                context: CodeActionContext {
+                    diagnostics,
+                    only: only.clone(),
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            }
+        })
+        .collect();
+    ctx.batch_call::<CodeActionRequest, _>(
+        meta,
+        req_params,
+        move |ctx: &mut Context, meta, results| {
+            // Actions that apply to several selections are offered once.
+            let result = results
+                .into_iter()
+                .flatten()
+                .flatten()
+                .unique_by(|action| serde_json::to_string(action).unwrap())
+                .collect::<Vec<_>>();
+            if auto_single {
+                if let Some(action) = single_preferred_quickfix(&result, &cursor_diagnostics) {
+                    resolve_and_apply_code_action(meta, action.clone(), ctx);
+                    return;
+                }
+            }
+            editor_code_actions(meta, Some(result), ctx)
         },
-        work_done_progress_params: Default::default(),
-        partial_result_params: Default::default(),
-    };
-    ctx.call::<CodeActionRequest, _>(meta, req_params, move |ctx: &mut Context, meta, result| {
-        editor_code_actions(meta, result, ctx)
+    );
+}
+
+/// The preferred quickfix for the diagnostics under the cursor, if there is exactly one.
+fn single_preferred_quickfix<'a>(
+    actions: &'a [CodeActionOrCommand],
+    cursor_diagnostics: &[Diagnostic],
+) -> Option<&'a CodeAction> {
+    let mut quickfixes = actions.iter().filter_map(|c| match c {
+        CodeActionOrCommand::CodeAction(action)
+            if action.is_preferred == Some(true)
+                && action.disabled.is_none()
+                && matches!(&action.kind, Some(kind) if kind.as_str().starts_with("quickfix"))
+                && match &action.diagnostics {
+                    Some(diagnostics) => diagnostics
+                        .iter()
+                        .any(|d| cursor_diagnostics.iter().any(|c| same_diagnostic(c, d))),
+                    None => !cursor_diagnostics.is_empty(),
+                } =>
+        {
+            Some(action)
+        }
+        _ => None,
     });
+    match (quickfixes.next(), quickfixes.next()) {
+        (Some(action), None) => Some(action),
+        _ => None,
+    }
+}
+
+/// Whether a diagnostic sent back by the server is one the client stores. Diagnostic rules may
+/// have changed the severity of the stored one, so only what identifies it is compared.
+fn same_diagnostic(stored: &Diagnostic, sent: &Diagnostic) -> bool {
+    stored.range == sent.range && stored.message == sent.message && stored.code == sent.code
 }
 
 pub fn editor_code_actions(
@@ -49,7 +129,7 @@ pub fn editor_code_actions(
     result: Option<CodeActionResponse>,
     ctx: &mut Context,
 ) {
-    let result = match result {
+    let mut result = match result {
         Some(result) => result,
         None => return,
     };
@@ -66,15 +146,16 @@ pub fn editor_code_actions(
         }
     }
 
+    // Preferred actions come first, disabled ones last.
+    result.sort_by_key(|c| match c {
+        CodeActionOrCommand::Command(_) => 1,
+        CodeActionOrCommand::CodeAction(action) if action.disabled.is_some() => 2,
+        CodeActionOrCommand::CodeAction(action) if action.is_preferred == Some(true) => 0,
+        CodeActionOrCommand::CodeAction(_) => 1,
+    });
+
     let titles_and_commands = result
         .iter()
-        .map(|c| match c {
-            CodeActionOrCommand::Command(_) => c.clone(),
-            CodeActionOrCommand::CodeAction(action) => match &action.command {
-                Some(cmd) => CodeActionOrCommand::Command(cmd.clone()),
-                None => c.clone(),
-            },
-        })
         .map(|c| match c {
             CodeActionOrCommand::Command(command) => {
                 let title = editor_quote(&command.title);
@@ -86,13 +167,23 @@ pub fn editor_code_actions(
                 let select_cmd = editor_quote(&format!("lsp-execute-command {} {}", cmd, args));
                 format!("{} {}", title, select_cmd)
             }
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title,
+                disabled: Some(disabled),
+                ..
+            }) => {
+                let title = editor_quote(&format!("{} (disabled: {})", title, disabled.reason));
+                let msg = format!("code action is disabled: {}", disabled.reason);
+                let select_cmd = editor_quote(&format!("lsp-show-error {}", editor_quote(&msg)));
+                format!("{} {}", title, select_cmd)
+            }
             CodeActionOrCommand::CodeAction(action) => {
                 let title = editor_quote(&action.title);
-                // Double JSON serialization is performed to prevent parsing args as a TOML
-                // structure when they are passed back via lsp-apply-workspace-edit.
-                let edit = &serde_json::to_string(&action.edit.unwrap()).unwrap();
-                let edit = editor_quote(&serde_json::to_string(&edit).unwrap());
-                let select_cmd = editor_quote(&format!("lsp-apply-workspace-edit {}", edit));
+                // Double JSON serialization is performed to prevent parsing the action as a TOML
+                // structure when it is passed back via lsp-apply-code-action.
+                let action = &serde_json::to_string(&action).unwrap();
+                let action = editor_quote(&serde_json::to_string(&action).unwrap());
+                let select_cmd = editor_quote(&format!("lsp-apply-code-action {}", action));
                 format!("{} {}", title, select_cmd)
             }
         })
@@ -102,3 +193,253 @@ pub fn editor_code_actions(
         format!("lsp-show-code-actions {}", titles_and_commands),
     );
 }
+
+#[derive(Deserialize)]
+struct EditorApplyCodeAction {
+    action: String,
+}
+
+/// Apply a code action selected in the editor, resolving its edit first if the server computes
+/// it lazily.
+pub fn apply_code_action_from_editor(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = EditorApplyCodeAction::deserialize(params).expect("Failed to parse params");
+    let action = CodeAction::deserialize(serde_json::from_str::<Value>(&params.action).unwrap())
+        .expect("Failed to parse code action");
+    resolve_and_apply_code_action(meta, action, ctx);
+}
+
+fn resolve_and_apply_code_action(meta: EditorMeta, action: CodeAction, ctx: &mut Context) {
+    if action.edit.is_none() && server_resolves_code_actions(ctx) {
+        ctx.call::<CodeActionResolveRequest, _>(
+            meta,
+            action,
+            move |ctx: &mut Context, meta, result| apply_code_action(meta, result, ctx),
+        );
+    } else {
+        apply_code_action(meta, action, ctx);
+    }
+}
+
+fn server_resolves_code_actions(ctx: &Context) -> bool {
+    match ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.code_action_provider.as_ref())
+    {
+        Some(CodeActionProviderCapability::Options(options)) => {
+            options.resolve_provider.unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// Apply the edit of a code action, then execute its command, as the specification requires.
+fn apply_code_action(meta: EditorMeta, action: CodeAction, ctx: &mut Context) {
+    if let Some(edit) = action.edit {
+        if !workspace::apply_edit(meta.clone(), edit, ctx).applied {
+            return;
+        }
+    }
+    if let Some(command) = action.command {
+        let req_params = ExecuteCommandParams {
+            command: command.command,
+            arguments: command.arguments.unwrap_or_default(),
+            work_done_progress_params: Default::default(),
+        };
+        workspace::execute_command_request(meta, req_params, ctx);
+    }
+}
+
+#[derive(Deserialize)]
+struct EditorCodeActionsOnSave {
+    only: Vec<String>,
+}
+
+/// Apply the code actions of the given kinds to the whole buffer before it is written. The
+/// editor is blocked waiting for the result via fifo, so all edits are sent back in one command,
+/// and none if the server takes too long.
+pub fn text_document_code_actions_on_save(
+    meta: EditorMeta,
+    params: EditorParams,
+    ctx: &mut Context,
+) {
+    let params = EditorCodeActionsOnSave::deserialize(params)
+        .expect("Params should follow EditorCodeActionsOnSave structure");
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            ctx.exec(meta, "nop");
+            return;
+        }
+    };
+    let end = char_to_lsp_position(
+        document.text.len_chars(),
+        &document.text,
+        ctx.offset_encoding,
+    );
+    let kinds = params.only;
+    let req_params = CodeActionParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        range: Range {
+            start: Position::default(),
+            end,
+        },
+        context: CodeActionContext {
+            diagnostics: ctx
+                .diagnostics
+                .get(&meta.buffile)
+                .cloned()
+                .unwrap_or_default(),
+            only: Some(kinds.iter().cloned().map(CodeActionKind::from).collect()),
+        },
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    let meta = begin_pre_save(meta, ctx);
+    let on_error = |ctx: &mut Context, _| finish_pre_save(ctx, "nop".to_string());
+    ctx.batch_call_or_else::<CodeActionRequest, _, _>(
+        meta,
+        vec![req_params],
+        move |ctx: &mut Context, meta, mut results| {
+            let mut actions = Vec::new();
+            // Commands can't be run before the buffer is written, as their edits are applied
+            // asynchronously by the server.
+            let mut skipped = Vec::new();
+            for c in results.pop().flatten().unwrap_or_default() {
+                match c {
+                    CodeActionOrCommand::Command(command) => skipped.push(command.title),
+                    CodeActionOrCommand::CodeAction(action) if action.disabled.is_none() => {
+                        actions.push(action)
+                    }
+                    CodeActionOrCommand::CodeAction(_) => (),
+                }
+            }
+            let (ready, unresolved): (Vec<_>, Vec<_>) = actions
+                .into_iter()
+                .partition(|action| action.edit.is_some());
+            if unresolved.is_empty() || !server_resolves_code_actions(ctx) {
+                editor_code_actions_on_save(meta, ready, &kinds, skipped, ctx);
+                return;
+            }
+            ctx.batch_call_or_else::<CodeActionResolveRequest, _, _>(
+                meta,
+                unresolved,
+                move |ctx: &mut Context, meta, resolved| {
+                    let actions = ready.into_iter().chain(resolved).collect();
+                    editor_code_actions_on_save(meta, actions, &kinds, skipped, ctx)
+                },
+                on_error,
+            );
+        },
+        on_error,
+    );
+}
+
+/// Combine the edits that actions make to the buffer, in the order of the kinds they were
+/// requested for. An action whose edits overlap those of an earlier action is skipped, as its
+/// edits were computed without the earlier ones.
+fn editor_code_actions_on_save(
+    meta: EditorMeta,
+    mut actions: Vec<CodeAction>,
+    kinds: &[String],
+    mut skipped: Vec<String>,
+    ctx: &mut Context,
+) {
+    actions.sort_by_key(|action| {
+        let kind = action.kind.as_ref().map_or("", |kind| kind.as_str());
+        kinds
+            .iter()
+            .position(|k| kind == k || kind.starts_with(&format!("{}.", k)))
+            .unwrap_or(kinds.len())
+    });
+    let uri = Url::from_file_path(&meta.buffile).unwrap();
+    let mut text_edits: Vec<OneOf<TextEdit, AnnotatedTextEdit>> = Vec::new();
+    for CodeAction {
+        title,
+        edit,
+        command,
+        ..
+    } in actions
+    {
+        if command.is_some() {
+            skipped.push(title.clone());
+        }
+        let edits = edit
+            .map(workspace::workspace_changes)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|change| match change {
+                workspace::WorkspaceChange::Edit(edit_uri, _, edits) if edit_uri == uri => {
+                    Some(edits)
+                }
+                _ => {
+                    warn!(
+                        "Ignoring change to another document from code action on save: {}",
+                        title
+                    );
+                    None
+                }
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        let overlaps = edits.iter().any(|edit| {
+            text_edits.iter().any(|other| {
+                let (a, b) = (text_edit_range(edit), text_edit_range(other));
+                a.start <= b.end && b.start <= a.end
+            })
+        });
+        if overlaps {
+            warn!(
+                "Skipping code action on save that conflicts with an earlier one: {}",
+                title
+            );
+            continue;
+        }
+        text_edits.extend(edits);
+    }
+    let cmd = ctx.documents.get(&meta.buffile).and_then(|document| {
+        apply_text_edits_to_buffer(
+            &meta.client,
+            None,
+            &text_edits,
+            &document.text,
+            ctx.offset_encoding,
+        )
+    });
+    // Nothing to do, but sending command back to the editor is required to handle case when
+    // editor is blocked waiting for response via fifo.
+    let mut cmd = cmd.unwrap_or_else(|| "nop".to_string());
+    if !skipped.is_empty() {
+        let msg = format!(
+            "kak-lsp: skipped commands of code actions on save: {}",
+            skipped.join(", ")
+        );
+        cmd = format!("{}\necho {}", cmd, editor_quote(&msg));
+    }
+    finish_pre_save(ctx, cmd);
+}
+
+fn text_edit_range(edit: &OneOf<TextEdit, AnnotatedTextEdit>) -> &Range {
+    match edit {
+        OneOf::Left(edit) => &edit.range,
+        OneOf::Right(edit) => &edit.text_edit.range,
+    }
+}
+
+/// Build the command to expose the code action kinds to apply on save to the editor as a buffer
+/// option.
+pub fn code_actions_on_save_command(buffile: &str, ctx: &Context) -> String {
+    let kinds = ctx
+        .config
+        .language
+        .get(&ctx.language_id)
+        .map(|language| language.code_actions_on_save.as_slice())
+        .unwrap_or_default();
+    format!(
+        "set-option buffer={} lsp_code_actions_on_save {}",
+        editor_quote(buffile),
+        kinds.iter().map(|kind| editor_quote(kind)).join(" ")
+    )
+}
@@ -1,17 +1,29 @@
 use crate::context::*;
 use crate::markup::*;
-use crate::position::lsp_range_to_kakoune;
+use crate::position::*;
+use crate::snippet::{expand_snippet, ExpandedSnippet};
+use crate::text_edit::apply_text_edits_to_buffer;
 use crate::types::*;
 use crate::util::*;
 use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
-use regex::Regex;
+use ropey::Rope;
 use serde::Deserialize;
+use std::cmp::{max, min};
+use std::path::Path;
 use url::Url;
 
 pub fn text_document_completion(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentCompletionParams::deserialize(params).unwrap();
+    if let Some(items) = cached_completion_items(&meta, &params, ctx) {
+        let list = CompletionList {
+            is_incomplete: false,
+            items,
+        };
+        editor_completion(meta, params, Some(CompletionResponse::List(list)), ctx);
+        return;
+    }
     let req_params = CompletionParams {
         text_document_position: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
@@ -19,7 +31,7 @@ pub fn text_document_completion(meta: EditorMeta, params: EditorParams, ctx: &mu
             },
             position: get_lsp_position(&meta.buffile, &params.position, ctx).unwrap(),
         },
-        context: None,
+        context: Some(completion_context(&meta, &params, ctx)),
         work_done_progress_params: Default::default(),
         partial_result_params: Default::default(),
     };
@@ -38,59 +50,173 @@ pub fn editor_completion(
         return;
     }
 
-    let items = match result.unwrap() {
-        CompletionResponse::Array(items) => items,
-        CompletionResponse::List(list) => list.items,
+    let (items, is_incomplete) = match result.unwrap() {
+        CompletionResponse::Array(items) => (items, false),
+        CompletionResponse::List(list) => (list.items, list.is_incomplete),
+    };
+    let cached_items = if is_incomplete {
+        Vec::new()
+    } else {
+        items.clone()
+    };
+
+    let text = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document.text.clone(),
+        None => {
+            warn!("No document in context for file: {}", &meta.buffile);
+            Rope::new()
+        }
     };
+    let can_resolve = can_resolve_completion_items(ctx);
 
     // Length of the longest label in the current completion list
     let maxlen = items.iter().map(|x| x.label.len()).max().unwrap_or(0);
     let escape_bar = |s: &str| s.replace("|", "\\|");
-    let snippet_prefix_re = Regex::new(r"^[^\[\(<\n\$]+").unwrap();
 
-    let mut inferred_offset: Option<u32> = None;
-    let mut can_infer_offset = true;
+    let cursor = params.position;
+    let word_start = params.completion.offset;
+    let line = get_line(cursor.line as usize - 1, &text).to_string();
+    // Text of the cursor line between two Kakoune columns.
+    let line_slice = |start: u32, end: u32| {
+        let start = min(start as usize - 1, line.len());
+        let end = min(end as usize - 1, line.len()).max(start);
+        line.get(start..end).unwrap_or("")
+    };
 
-    let items = items
-        .into_iter()
-        .map(|x| {
-            let doc = x.documentation.map(|doc| match doc {
-                Documentation::String(s) => s,
-                Documentation::MarkupContent(content) => content.value,
-            });
+    let edits = items
+        .iter()
+        .map(|x| completion_item_edit(x, &cursor, &text, ctx.offset_encoding))
+        .collect::<Vec<_>>();
+    // Items without text edits replace the same token as the others if they agree on it, which
+    // is more reliable than the token detected on the Kakoune side (#378).
+    let default_start = edits
+        .iter()
+        .flatten()
+        .map(|edit| edit.start)
+        .dedup()
+        .exactly_one()
+        .unwrap_or(word_start);
 
-            // Combine the 'detail' line and the full-text documentation into
-            // a single string. If both exist, separate them with a horizontal rule.
-            let markdown = {
-                let mut markdown = String::new();
+    // Kakoune only matches the typed text against the candidates' insert text, so filter and rank
+    // the items here to take the server's filter text, sort text and preselection into account.
+    let mut ranked = items
+        .into_iter()
+        .zip(edits)
+        .filter_map(|(x, edit)| {
+            let start = edit.as_ref().map_or(default_start, |edit| edit.start);
+            let query = line_slice(start, cursor.column);
+            let filter_text = x.filter_text.as_deref().unwrap_or(&x.label);
+            let score = fuzzy_score(query, filter_text)?;
+            Some((x, edit, score))
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(a, _, a_score), (b, _, b_score)| {
+        let sort_text = |x: &CompletionItem| x.sort_text.clone().unwrap_or_else(|| x.label.clone());
+        b.preselect
+            .unwrap_or(false)
+            .cmp(&a.preselect.unwrap_or(false))
+            .then(b_score.cmp(a_score))
+            .then_with(|| sort_text(a).cmp(&sort_text(b)))
+    });
+    if ctx.config.completion_limit > 0 {
+        ranked.truncate(ctx.config.completion_limit);
+    }
+    let (items, edits): (Vec<_>, Vec<_>) = ranked.into_iter().map(|(x, edit, _)| (x, edit)).unzip();
 
-                if let Some(detail) = x.detail {
-                    markdown.push_str(&detail);
+    // Kakoune replaces the text between the completion offset and the cursor with the selected
+    // candidate. Text edits starting later are prefixed with the text they leave alone, and text
+    // they replace right of the cursor is deleted once the candidate is accepted.
+    let offset = edits
+        .iter()
+        .flatten()
+        .map(|edit| edit.start)
+        .chain(std::iter::once(default_start))
+        .min()
+        .unwrap();
 
-                    if doc.is_some() {
-                        markdown.push_str("\n\n---\n\n");
-                    }
-                }
+    // Lines of multi-line snippets are aligned with the cursor line.
+    let indent = line
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect::<String>();
+    let tab = match params.completion.indent_width {
+        0 => "\t".to_string(),
+        width => " ".repeat(width),
+    };
+    let path = Path::new(&meta.buffile);
+    let path_component =
+        |component: Option<&std::ffi::OsStr>| component.map(|s| s.to_string_lossy().into_owned());
+    let variables = |name: &str| match name {
+        "TM_SELECTED_TEXT" => Some(String::new()),
+        "TM_CURRENT_LINE" => Some(line.trim_end_matches('\n').to_string()),
+        "TM_CURRENT_WORD" => Some(line_slice(word_start, cursor.column).to_string()),
+        "TM_LINE_INDEX" => Some((cursor.line - 1).to_string()),
+        "TM_LINE_NUMBER" => Some(cursor.line.to_string()),
+        "TM_FILENAME" => path_component(path.file_name()),
+        "TM_FILENAME_BASE" => path_component(path.file_stem()),
+        "TM_DIRECTORY" => path_component(path.parent().map(|p| p.as_os_str())),
+        "TM_FILEPATH" => Some(meta.buffile.clone()),
+        _ => None,
+    };
 
-                if let Some(doc) = doc {
-                    markdown.push_str(&doc);
+    let mut snippets = Vec::with_capacity(items.len());
+    let insert_texts = items
+        .iter()
+        .zip(&edits)
+        .map(|(x, edit)| {
+            let (start, new_text) = match edit {
+                Some(edit) => (edit.start, edit.new_text.clone()),
+                None => (
+                    default_start,
+                    x.insert_text.clone().unwrap_or_else(|| x.label.clone()),
+                ),
+            };
+            let prefix = line_slice(offset, start);
+            // If snippet support is both enabled and provided by the server, insert the expanded
+            // snippet. Its placeholders are selected once the candidate is accepted.
+            if ctx.config.snippet_support && x.insert_text_format == Some(InsertTextFormat::Snippet)
+            {
+                let mut snippet = expand_snippet(&new_text, &indent, &tab, &variables);
+                let prefix_len = prefix.chars().count();
+                for placeholder in &mut snippet.placeholders {
+                    placeholder.start += prefix_len;
+                    placeholder.end += prefix_len;
                 }
+                snippet.text = prefix.to_string() + &snippet.text;
+                let insert_text = snippet.text.clone();
+                snippets.push(Some(snippet).filter(|s| !s.placeholders.is_empty()));
+                insert_text
+            } else {
+                snippets.push(None);
+                prefix.to_string() + &new_text
+            }
+        })
+        .collect::<Vec<_>>();
 
-                markdown
-            };
+    let on_accept = can_resolve
+        || snippets.iter().any(|snippet| snippet.is_some())
+        || items.iter().zip(&edits).any(|(x, edit)| match edit {
+            Some(edit) => x.additional_text_edits.is_some() || edit.end > cursor.column,
+            None => x.additional_text_edits.is_some(),
+        });
 
-            let doc = if !markdown.is_empty() {
-                let markup = markdown_to_kakoune_markup(markdown);
-                format!(
-                    "info -markup -style menu -- %§{}§",
-                    markup.replace("§", "\\§")
-                )
-            } else {
-                // When the user scrolls through the list of completion candidates, Kakoune
-                // does not clean up the info box. We need to do that explicitly, in this case by
-                // requesting an empty one.
-                "info -style menu ''".to_string()
-            };
+    let candidates = items
+        .iter()
+        .zip(insert_texts)
+        .enumerate()
+        .map(|(i, (x, insert_text))| {
+            let mut doc = completion_item_info(x);
+            // Servers may leave out the documentation until the item is resolved, so resolve
+            // it when the candidate is selected in the menu.
+            if can_resolve && x.documentation.is_none() {
+                doc = format!("{}\nlsp-completion-item-resolve {}", doc, i);
+            }
+            // Snippet placeholders, additional text edits (e.g. auto-imports) and the deletion of
+            // text replaced right of the cursor are applied once the candidate is accepted.
+            // Additional text edits might only be known after resolving the item.
+            if on_accept {
+                doc = format!("{}\nlsp-completion-on-accept {}", doc, i);
+            }
 
             let entry = match x.kind {
                 Some(k) => format!(
@@ -102,101 +228,462 @@ pub fn editor_completion(
                 None => x.label.clone(),
             };
 
-            let insert_text = x
-                .text_edit
-                .and_then(|cte| {
-                    let document = match ctx.documents.get(&meta.buffile) {
-                        Some(doc) => doc,
-                        None => {
-                            warn!("No document in context for file: {}", &meta.buffile);
-                            can_infer_offset = false;
-                            return None;
-                        }
-                    };
-
-                    match cte {
-                        CompletionTextEdit::Edit(text_edit) => {
-                            // The generic textEdit property is not supported yet (#40).  However,
-                            // we can support simple text edits that only replace the token left
-                            // of the cursor. Kakoune will do this very edit if we simply pass it
-                            // the replacement string as completion.
-                            let range = lsp_range_to_kakoune(
-                                &text_edit.range,
-                                &document.text,
-                                ctx.offset_encoding,
-                            );
-
-                            if can_infer_offset {
-                                match inferred_offset {
-                                    None => inferred_offset = Some(range.start.column),
-                                    Some(offset) if offset != range.start.column => {
-                                        can_infer_offset = false;
-                                        inferred_offset = None
-                                    }
-                                    _ => (),
-                                }
-                            };
-
-                            if range.start.line == params.position.line
-                            && range.end.line == params.position.line
-                            // Not sure why this case happens, see #455
-                            && (range.end.column == params.position.column
-                                || range.end.column + 1 == params.position.column)
-                            {
-                                Some(text_edit.new_text)
-                            } else {
-                                None
-                            }
-                        }
-                        CompletionTextEdit::InsertAndReplace(_) => {
-                            can_infer_offset = false;
-                            None
-                        }
-                    }
-                })
-                .or(x.insert_text)
-                .unwrap_or(x.label);
-
-            // If snippet support is both enabled and provided by the server,
-            // we'll need to perform some transformations on the completion commands.
-            if ctx.config.snippet_support && x.insert_text_format == Some(InsertTextFormat::Snippet)
-            {
-                let snippet = insert_text;
-                let insert_text = snippet_prefix_re
-                    .find(&snippet)
-                    .map(|x| x.as_str())
-                    .unwrap_or(&snippet);
-
-                let command = format!(
-                    "{}\nlsp-snippets-insert-completion {} {}",
-                    doc,
-                    editor_quote(&regex::escape(insert_text)),
-                    editor_quote(&snippet)
-                );
-
-                editor_quote(&format!(
-                    "{}|{}|{}",
-                    escape_bar(insert_text),
-                    escape_bar(&command),
-                    escape_bar(&entry),
-                ))
-            } else {
-                editor_quote(&format!(
-                    "{}|{}|{}",
-                    escape_bar(&insert_text),
-                    escape_bar(&doc),
-                    escape_bar(&entry),
-                ))
-            }
+            editor_quote(&format!(
+                "{}|{}|{}",
+                escape_bar(&insert_text),
+                escape_bar(&doc),
+                escape_bar(&entry),
+            ))
         })
         .join(" ");
 
-    let p = params.position;
-    let offset = inferred_offset.unwrap_or(params.completion.offset);
+    ctx.completion = CompletionState {
+        items,
+        position: kakoune_position_to_lsp(&cursor, &text, ctx.offset_encoding),
+        text,
+        snippets,
+        buffile: meta.buffile.clone(),
+        token_start: word_start,
+        cached_items,
+        is_incomplete,
+        resolve_index: None,
+    };
+
     let command = format!(
         "set window lsp_completions {}.{}@{} {}\n",
-        p.line, offset, meta.version, items
+        cursor.line, offset, meta.version, candidates
     );
 
     ctx.exec(meta, command);
 }
+
+/// Tell the server why completion was requested.
+fn completion_context(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    ctx: &Context,
+) -> CompletionContext {
+    let cache = &ctx.completion;
+    if cache.is_incomplete
+        && cache.buffile == meta.buffile
+        && cache.token_start == params.completion.offset
+    {
+        return CompletionContext {
+            trigger_kind: CompletionTriggerKind::TriggerForIncompleteCompletions,
+            trigger_character: None,
+        };
+    }
+    let before_cursor = ctx.documents.get(&meta.buffile).map(|document| {
+        let line = get_line(params.position.line as usize - 1, &document.text).to_string();
+        let end = min(params.position.column as usize - 1, line.len());
+        line.get(..end).unwrap_or_default().to_string()
+    });
+    let trigger_character = before_cursor.and_then(|before_cursor| {
+        completion_trigger_characters(ctx)
+            .iter()
+            .find(|c| !c.is_empty() && before_cursor.ends_with(c.as_str()))
+            .cloned()
+    });
+    CompletionContext {
+        trigger_kind: if trigger_character.is_some() {
+            CompletionTriggerKind::TriggerCharacter
+        } else {
+            CompletionTriggerKind::Invoked
+        },
+        trigger_character,
+    }
+}
+
+fn completion_trigger_characters(ctx: &Context) -> &[String] {
+    ctx.capabilities
+        .as_ref()
+        .and_then(|caps| caps.completion_provider.as_ref())
+        .and_then(|provider| provider.trigger_characters.as_deref())
+        .unwrap_or_default()
+}
+
+/// Build the command to expose the server's completion trigger characters to the editor as a
+/// buffer option.
+pub fn trigger_characters_command(buffile: &str, ctx: &Context) -> String {
+    format!(
+        "set-option buffer={} lsp_completion_trigger_characters {}",
+        editor_quote(buffile),
+        completion_trigger_characters(ctx)
+            .iter()
+            .map(|c| editor_quote(c))
+            .join(" ")
+    )
+}
+
+/// Get the items of the last completion list again if the list was complete and the user only
+/// typed more of the same token since. Their text edits are extended to the new cursor position.
+fn cached_completion_items(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    ctx: &Context,
+) -> Option<Vec<CompletionItem>> {
+    let cache = &ctx.completion;
+    if cache.cached_items.is_empty()
+        || cache.buffile != meta.buffile
+        || cache.token_start != params.completion.offset
+    {
+        return None;
+    }
+    let old_text = &cache.text;
+    let text = &ctx.documents.get(&meta.buffile)?.text;
+    let old_position = cache.position;
+    let position = kakoune_position_to_lsp(&params.position, text, ctx.offset_encoding);
+    if position.line != old_position.line || position.character < old_position.character {
+        return None;
+    }
+    // The line up to the old cursor must be unchanged, and only word characters typed since.
+    let line_start =
+        |text: &Rope| text.line_to_char(min(position.line as usize, text.len_lines() - 1));
+    let old_end = lsp_position_to_char(&old_position, old_text, ctx.offset_encoding);
+    let end = lsp_position_to_char(&position, text, ctx.offset_encoding);
+    let typed_start = line_start(text) + old_end - line_start(old_text);
+    if typed_start > end
+        || old_text.slice(line_start(old_text)..old_end)
+            != text.slice(line_start(text)..typed_start)
+        || !text
+            .slice(typed_start..end)
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_')
+    {
+        return None;
+    }
+
+    let delta = position.character - old_position.character;
+    let extend = |end: &mut Position| {
+        if end.line == old_position.line && end.character >= old_position.character {
+            end.character += delta;
+        }
+    };
+    let mut items = cache.cached_items.clone();
+    for item in &mut items {
+        match &mut item.text_edit {
+            Some(CompletionTextEdit::Edit(edit)) => extend(&mut edit.range.end),
+            Some(CompletionTextEdit::InsertAndReplace(edit)) => {
+                extend(&mut edit.insert.end);
+                extend(&mut edit.replace.end);
+            }
+            None => (),
+        }
+    }
+    Some(items)
+}
+
+// Part of the cursor line replaced by a completion item's text edit.
+struct CompletionItemEdit {
+    // Kakoune column where the replaced text starts.
+    start: u32,
+    // Kakoune column where the replaced text ends, exclusive.
+    end: u32,
+    new_text: String,
+}
+
+/// Get the part of the cursor line replaced by the item's text edit, if it has one.
+/// Returns None if the edit doesn't fit Kakoune's completion, i.e. it spans several lines or
+/// doesn't contain the cursor.
+fn completion_item_edit(
+    item: &CompletionItem,
+    cursor: &KakounePosition,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Option<CompletionItemEdit> {
+    let (range, new_text) = match item.text_edit.as_ref()? {
+        CompletionTextEdit::Edit(edit) => (edit.range, &edit.new_text),
+        // Insert mode is the natural choice since Kakoune only replaces text up to the cursor.
+        CompletionTextEdit::InsertAndReplace(edit) => (edit.insert, &edit.new_text),
+    };
+    let start = lsp_position_to_kakoune(&range.start, text, offset_encoding);
+    let mut end = lsp_position_to_kakoune(&range.end, text, offset_encoding);
+    // Not sure why this case happens, see #455
+    if end.line == cursor.line && end.column + 1 == cursor.column {
+        end.column = cursor.column;
+    }
+    if start.line != cursor.line
+        || end.line != cursor.line
+        || start.column > cursor.column
+        || end.column < cursor.column
+    {
+        return None;
+    }
+    Some(CompletionItemEdit {
+        start: start.column,
+        end: end.column,
+        new_text: new_text.clone(),
+    })
+}
+
+/// Score how well a candidate matches the typed text, which must be a subsequence of the candidate
+/// ignoring case. Returns None if it doesn't match. Matches at the start of the candidate or of its
+/// words, consecutive matches and matches with the same case score higher, gaps score lower.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let candidate = candidate.chars().collect::<Vec<_>>();
+    let is_word_start = |i: usize| {
+        i == 0
+            || !candidate[i - 1].is_alphanumeric() && candidate[i].is_alphanumeric()
+            || candidate[i - 1].is_lowercase() && candidate[i].is_uppercase()
+    };
+    let mut score = 0;
+    let mut next = 0;
+    for c in query.chars() {
+        let i = (next..candidate.len())
+            .find(|&i| candidate[i] == c || candidate[i].to_lowercase().eq(c.to_lowercase()))?;
+        score += 1;
+        if candidate[i] == c {
+            score += 1;
+        }
+        if i == 0 {
+            score += 8;
+        } else if i == next && next > 0 {
+            score += 5;
+        } else if is_word_start(i) {
+            score += 4;
+        }
+        score -= min(i - next, 5) as i64;
+        next = i + 1;
+    }
+    Some(score)
+}
+
+fn can_resolve_completion_items(ctx: &Context) -> bool {
+    ctx.capabilities
+        .as_ref()
+        .and_then(|caps| caps.completion_provider.as_ref())
+        .and_then(|provider| provider.resolve_provider)
+        .unwrap_or(false)
+}
+
+/// Build the command to show the detail line and documentation of a completion item in an info
+/// box next to the completion menu.
+fn completion_item_info(item: &CompletionItem) -> String {
+    let doc = item.documentation.as_ref().map(|doc| match doc {
+        Documentation::String(s) => s,
+        Documentation::MarkupContent(content) => &content.value,
+    });
+
+    // Combine the 'detail' line and the full-text documentation into
+    // a single string. If both exist, separate them with a horizontal rule.
+    let markdown = {
+        let mut markdown = String::new();
+
+        if let Some(detail) = &item.detail {
+            markdown.push_str(detail);
+
+            if doc.is_some() {
+                markdown.push_str("\n\n---\n\n");
+            }
+        }
+
+        if let Some(doc) = doc {
+            markdown.push_str(doc);
+        }
+
+        markdown
+    };
+
+    if !markdown.is_empty() {
+        let markup = markdown_to_kakoune_markup(markdown);
+        format!(
+            "info -markup -style menu -- %§{}§",
+            markup.replace("§", "\\§")
+        )
+    } else {
+        // When the user scrolls through the list of completion candidates, Kakoune
+        // does not clean up the info box. We need to do that explicitly, in this case by
+        // requesting an empty one.
+        "info -style menu ''".to_string()
+    }
+}
+
+pub fn completion_item_resolve(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CompletionItemResolveParams::deserialize(params)
+        .expect("Params should follow CompletionItemResolveParams structure");
+    let item = match ctx.completion.items.get(params.index) {
+        Some(item) => item.clone(),
+        None => return,
+    };
+    ctx.completion.resolve_index = Some(params.index);
+    ctx.call::<ResolveCompletionItem, _>(meta, item, move |ctx: &mut Context, meta, result| {
+        editor_completion_item_resolve(meta, params, result, ctx)
+    });
+}
+
+pub fn editor_completion_item_resolve(
+    meta: EditorMeta,
+    params: CompletionItemResolveParams,
+    item: CompletionItem,
+    ctx: &mut Context,
+) {
+    // The completion list may have been replaced, or another candidate selected, while the
+    // request was in flight.
+    if ctx.completion.resolve_index != Some(params.index) {
+        return;
+    }
+    match ctx.completion.items.get(params.index) {
+        Some(x) if x.label == item.label => (),
+        _ => return,
+    }
+    ctx.exec(meta, completion_item_info(&item));
+    ctx.completion.items[params.index] = item;
+}
+
+pub fn completion_item_accept(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = CompletionItemAcceptParams::deserialize(params)
+        .expect("Params should follow CompletionItemAcceptParams structure");
+    let item = match ctx.completion.items.get(params.index) {
+        Some(item) => item.clone(),
+        None => return,
+    };
+    if item.additional_text_edits.is_none() && can_resolve_completion_items(ctx) {
+        ctx.call::<ResolveCompletionItem, _>(meta, item, move |ctx: &mut Context, meta, item| {
+            editor_completion_item_accept(meta, params, item, ctx)
+        });
+    } else {
+        editor_completion_item_accept(meta, params, item, ctx);
+    }
+}
+
+pub fn editor_completion_item_accept(
+    meta: EditorMeta,
+    params: CompletionItemAcceptParams,
+    item: CompletionItem,
+    ctx: &mut Context,
+) {
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            warn!("No document in context for file: {}", &meta.buffile);
+            return;
+        }
+    };
+    let text = &document.text;
+    let mut commands = Vec::new();
+
+    if let Some(Some(snippet)) = ctx.completion.snippets.get(params.index) {
+        if let Some(command) = snippet_placeholders(snippet, &params.position, text, meta.version) {
+            commands.push(command);
+        }
+    }
+
+    let mut edits = item.additional_text_edits.unwrap_or_default();
+    // Kakoune doesn't replace text right of the cursor, so delete what the item's text edit
+    // replaces there.
+    if let Some(text_edit) = &item.text_edit {
+        let position = ctx.completion.position;
+        let range = match text_edit {
+            CompletionTextEdit::Edit(edit) => edit.range,
+            CompletionTextEdit::InsertAndReplace(edit) => edit.insert,
+        };
+        if range.start.line == position.line
+            && range.end.line == position.line
+            && range.start <= position
+            && range.end > position
+        {
+            edits.push(TextEdit {
+                range: Range {
+                    start: position,
+                    end: range.end,
+                },
+                new_text: String::new(),
+            });
+        }
+    }
+    // Edits are relative to the buffer content the completion was computed against, which
+    // doesn't contain the accepted candidate yet.
+    let old_text = &ctx.completion.text;
+    let edits = match TextChange::between(old_text, text) {
+        Some(change) => edits
+            .into_iter()
+            .map(|edit| {
+                let map =
+                    |position| change.map_position(position, old_text, text, ctx.offset_encoding);
+                TextEdit {
+                    range: Range {
+                        start: map(&edit.range.start),
+                        end: map(&edit.range.end),
+                    },
+                    new_text: edit.new_text,
+                }
+            })
+            .map(OneOf::Left)
+            .collect::<Vec<_>>(),
+        None => edits.into_iter().map(OneOf::Left).collect(),
+    };
+    // Edit in a draft context so that Kakoune adjusts the client's selections, which are still
+    // in insert mode.
+    if let Some(command) =
+        apply_text_edits_to_buffer(&None, None, &edits, text, ctx.offset_encoding)
+    {
+        commands.push(command);
+    }
+
+    if !commands.is_empty() {
+        ctx.exec(meta, commands.join("\n"));
+    }
+}
+
+/// Build the command to highlight the placeholders of an inserted snippet, and select the first
+/// ones. Returns None if the snippet doesn't end at the cursor, i.e. it was not inserted.
+fn snippet_placeholders(
+    snippet: &ExpandedSnippet,
+    cursor: &KakounePosition,
+    text: &Rope,
+    version: i32,
+) -> Option<String> {
+    let end = kakoune_position_to_char(cursor, text);
+    let start = end.checked_sub(snippet.text.chars().count())?;
+    if text.slice(start..end) != snippet.text.as_str() {
+        return None;
+    }
+    let tabstops = snippet.tabstops();
+    let mut ranges = Vec::new();
+    let mut groups = Vec::new();
+    let mut empty = Vec::new();
+    for placeholder in &snippet.placeholders {
+        // Empty placeholders are represented by the character following them.
+        let range = KakouneRange {
+            start: char_to_kakoune_position(start + placeholder.start, text),
+            end: char_to_kakoune_position(
+                start + max(placeholder.end, placeholder.start + 1) - 1,
+                text,
+            ),
+        };
+        ranges.push(format!("{}|SnippetsOtherPlaceholders", range));
+        groups.push(
+            tabstops
+                .iter()
+                .position(|&tabstop| tabstop == placeholder.tabstop)
+                .unwrap()
+                + 1,
+        );
+        empty.push((placeholder.start == placeholder.end) as u8);
+    }
+    Some(format!(
+        "set-option window lsp_snippets_placeholders {} {}
+set-option window lsp_snippets_placeholder_groups {}
+set-option window lsp_snippets_placeholder_empty {}
+set-option window lsp_snippets_placeholder_current 0
+lsp-snippets-select-next-placeholders -clear",
+        version,
+        ranges.join(" "),
+        groups.iter().join(" "),
+        empty.iter().join(" ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_score_prefers_prefix_and_word_start_matches() {
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("xyz", "foo"), None);
+        assert!(fuzzy_score("fo", "foo").unwrap() > fuzzy_score("fo", "afoo").unwrap());
+        assert!(fuzzy_score("gl", "getLength").unwrap() > fuzzy_score("gl", "gold").unwrap());
+        assert!(fuzzy_score("Foo", "Foo").unwrap() > fuzzy_score("Foo", "foo").unwrap());
+    }
+}
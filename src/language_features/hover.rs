@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::diagnostics;
 use crate::markup::*;
 use crate::types::*;
 use crate::util::*;
@@ -62,13 +63,26 @@ pub fn editor_hover(
                         .unwrap_or(FACE_INFO_DEFAULT);
 
                     if !x.message.is_empty() {
-                        Some(format!(
+                        let mut entry = format!(
                             "• {{{}}}{}",
                             face,
                             escape_brace(x.message.trim())
                                 // Indent line breaks to the same level as the bullet point
                                 .replace("\n", "\n  "),
-                        ))
+                        );
+                        if let Some(code_description) = &x.code_description {
+                            entry.push_str(&format!(
+                                "\n  {{{}}}{}{{{}}}",
+                                FACE_INFO_LINK,
+                                escape_brace(code_description.href.as_str()),
+                                FACE_INFO_DEFAULT
+                            ));
+                        }
+                        for location in diagnostics::related_information_locations(x, ctx) {
+                            entry.push_str("\n  ");
+                            entry.push_str(&escape_brace(&location).replace("\n", "\n  "));
+                        }
+                        Some(entry)
                     } else {
                         None
                     }
@@ -0,0 +1,385 @@
+//! Pull diagnostics, where the client asks the server for diagnostics instead of waiting for
+//! `textDocument/publishDiagnostics`. They were added in LSP 3.17, which lsp-types doesn't
+//! support yet, so the protocol types are defined here.
+use crate::context::*;
+use crate::diagnostics;
+use crate::types::*;
+use jsonrpc_core::Params;
+use lsp_types::request::Request;
+use lsp_types::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+pub enum DocumentDiagnosticRequest {}
+
+impl Request for DocumentDiagnosticRequest {
+    type Params = DocumentDiagnosticParams;
+    type Result = DocumentDiagnosticReport;
+    const METHOD: &'static str = "textDocument/diagnostic";
+}
+
+pub enum WorkspaceDiagnosticRequest {}
+
+impl Request for WorkspaceDiagnosticRequest {
+    type Params = WorkspaceDiagnosticParams;
+    type Result = WorkspaceDiagnosticReport;
+    const METHOD: &'static str = "workspace/diagnostic";
+}
+
+pub const WORKSPACE_DIAGNOSTIC_REFRESH: &str = "workspace/diagnostic/refresh";
+
+/// Token of the partial results of `workspace/diagnostic`, which are streamed via `$/progress`.
+const WORKSPACE_DIAGNOSTIC_TOKEN: &str = "kak-lsp-workspace-diagnostic";
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentDiagnosticParams {
+    pub text_document: TextDocumentIdentifier,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_result_id: Option<String>,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+    #[serde(flatten)]
+    pub partial_result_params: PartialResultParams,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DocumentDiagnosticReport {
+    Full(FullDocumentDiagnosticReport),
+    Unchanged(UnchangedDocumentDiagnosticReport),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FullDocumentDiagnosticReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<String>,
+    pub items: Vec<Diagnostic>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UnchangedDocumentDiagnosticReport {
+    pub result_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkspaceDiagnosticParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    pub previous_result_ids: Vec<PreviousResultId>,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+    #[serde(flatten)]
+    pub partial_result_params: PartialResultParams,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PreviousResultId {
+    pub uri: Url,
+    pub value: String,
+}
+
+/// The result of `workspace/diagnostic`, and of each of its partial results.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceDiagnosticReport {
+    pub items: Vec<WorkspaceDocumentDiagnosticReport>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WorkspaceDocumentDiagnosticReport {
+    Full(WorkspaceFullDocumentDiagnosticReport),
+    Unchanged(WorkspaceUnchangedDocumentDiagnosticReport),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceFullDocumentDiagnosticReport {
+    pub uri: Url,
+    pub version: Option<i32>,
+    #[serde(flatten)]
+    pub report: FullDocumentDiagnosticReport,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceUnchangedDocumentDiagnosticReport {
+    pub uri: Url,
+    pub version: Option<i32>,
+    #[serde(flatten)]
+    pub report: UnchangedDocumentDiagnosticReport,
+}
+
+#[derive(Deserialize, Debug)]
+struct PartialResultProgress {
+    token: NumberOrString,
+    value: Value,
+}
+
+/// The server's `diagnosticProvider` capability.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DiagnosticOptions {
+    pub identifier: Option<String>,
+    #[serde(default)]
+    pub inter_file_dependencies: bool,
+    #[serde(default)]
+    pub workspace_diagnostics: bool,
+}
+
+/// The last diagnostics pulled for a document, reused when the server reports them unchanged.
+pub struct PulledDiagnostics {
+    pub result_id: Option<String>,
+    pub items: Vec<Diagnostic>,
+}
+
+/// Advertise support for pull diagnostics in the client capabilities sent on initialization.
+pub fn add_client_capabilities(capabilities: &mut Value) {
+    capabilities["textDocument"]["diagnostic"] = json!({
+        "dynamicRegistration": false,
+        "relatedDocumentSupport": false,
+    });
+    capabilities["workspace"]["diagnostics"] = json!({ "refreshSupport": true });
+}
+
+/// Read the server's support for pull diagnostics from its initialize result.
+pub fn server_capabilities(result: &Value) -> Option<DiagnosticOptions> {
+    serde_json::from_value(result["capabilities"]["diagnosticProvider"].clone()).ok()
+}
+
+pub fn text_document_diagnostic(meta: EditorMeta, ctx: &mut Context) {
+    let options = match &ctx.diagnostic_provider {
+        Some(options) => options,
+        None => return,
+    };
+    let params = DocumentDiagnosticParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        identifier: options.identifier.clone(),
+        previous_result_id: ctx
+            .pulled_diagnostics
+            .get(&meta.buffile)
+            .and_then(|pulled| pulled.result_id.clone()),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    };
+    // A slow response to the previous request would overwrite the newer diagnostics.
+    if let Some(id) = ctx.document_diagnostics_requests.remove(&meta.buffile) {
+        ctx.cancel(id);
+    }
+    let buffile = meta.buffile.clone();
+    let id = ctx
+        .batch_call_or_else::<DocumentDiagnosticRequest, _, _>(
+            meta,
+            vec![params],
+            move |ctx: &mut Context, meta, mut reports| {
+                ctx.document_diagnostics_requests.remove(&meta.buffile);
+                match ctx.documents.get(&meta.buffile) {
+                    Some(document) if meta.version >= document.version => (),
+                    _ => return,
+                }
+                if let Some(report) = reports.pop() {
+                    editor_document_diagnostic(&meta.buffile, report, ctx)
+                }
+            },
+            |ctx: &mut Context, meta| {
+                ctx.document_diagnostics_requests.remove(&meta.buffile);
+            },
+        )
+        .pop();
+    if let Some(id) = id {
+        ctx.document_diagnostics_requests.insert(buffile, id);
+    }
+}
+
+/// Pull diagnostics for a document that was saved. If diagnostics of a document depend on other
+/// documents, those that are open in the editor are pulled again too.
+pub fn text_document_diagnostic_on_save(meta: EditorMeta, ctx: &mut Context) {
+    let inter_file_dependencies = match &ctx.diagnostic_provider {
+        Some(options) => options.inter_file_dependencies,
+        None => return,
+    };
+    if !inter_file_dependencies {
+        text_document_diagnostic(meta, ctx);
+        return;
+    }
+    let buffiles = ctx.documents.keys().cloned().collect::<Vec<_>>();
+    for buffile in buffiles {
+        let meta = if buffile == meta.buffile {
+            meta.clone()
+        } else {
+            ctx.meta_for_buffer(&buffile).unwrap()
+        };
+        text_document_diagnostic(meta, ctx);
+    }
+}
+
+fn editor_document_diagnostic(buffile: &str, report: DocumentDiagnosticReport, ctx: &mut Context) {
+    let items = match report {
+        DocumentDiagnosticReport::Full(report) => {
+            let pulled = PulledDiagnostics {
+                result_id: report.result_id,
+                items: report.items.clone(),
+            };
+            ctx.pulled_diagnostics.insert(buffile.to_string(), pulled);
+            report.items
+        }
+        DocumentDiagnosticReport::Unchanged(report) => {
+            match ctx.pulled_diagnostics.get_mut(buffile) {
+                Some(pulled) => {
+                    pulled.result_id = Some(report.result_id);
+                    pulled.items.clone()
+                }
+                None => return,
+            }
+        }
+    };
+    diagnostics::update_diagnostics(buffile, items, ctx);
+}
+
+/// Handle `workspace/diagnostic/refresh`, sent by the server when diagnostics need to be pulled
+/// again for every open document.
+pub fn workspace_diagnostic_refresh(ctx: &mut Context) -> Result<Value, jsonrpc_core::Error> {
+    let buffiles = ctx.documents.keys().cloned().collect::<Vec<_>>();
+    for buffile in buffiles {
+        let meta = ctx.meta_for_buffer(&buffile).unwrap();
+        text_document_diagnostic(meta, ctx);
+    }
+    Ok(Value::Null)
+}
+
+/// Request diagnostics for the whole project, including files that are not open in the editor.
+/// The project diagnostics buffer is rendered again as partial results stream in.
+pub fn workspace_diagnostic(meta: EditorMeta, ctx: &mut Context) {
+    let options = match &ctx.diagnostic_provider {
+        Some(options) if options.workspace_diagnostics => options,
+        _ => return,
+    };
+    let params = WorkspaceDiagnosticParams {
+        identifier: options.identifier.clone(),
+        previous_result_ids: ctx
+            .pulled_diagnostics
+            .iter()
+            .filter_map(|(buffile, pulled)| {
+                Some(PreviousResultId {
+                    uri: Url::from_file_path(buffile).ok()?,
+                    value: pulled.result_id.clone()?,
+                })
+            })
+            .collect(),
+        work_done_progress_params: Default::default(),
+        partial_result_params: PartialResultParams {
+            partial_result_token: Some(NumberOrString::String(
+                WORKSPACE_DIAGNOSTIC_TOKEN.to_string(),
+            )),
+        },
+    };
+    // Results of the previous request would be rendered over the new ones.
+    if let Some((id, _)) = ctx.workspace_diagnostics_request.take() {
+        ctx.cancel(id);
+    }
+    let id = ctx
+        .batch_call_or_else::<WorkspaceDiagnosticRequest, _, _>(
+            meta.clone(),
+            vec![params],
+            move |ctx: &mut Context, meta, mut reports| {
+                ctx.workspace_diagnostics_request = None;
+                if let Some(report) = reports.pop() {
+                    editor_workspace_diagnostic(meta, report, ctx)
+                }
+            },
+            |ctx: &mut Context, _| ctx.workspace_diagnostics_request = None,
+        )
+        .pop();
+    ctx.workspace_diagnostics_request = id.map(|id| (id, meta));
+}
+
+/// Handle `$/progress` notifications that carry partial results of `workspace/diagnostic`.
+pub fn progress(params: Params, ctx: &mut Context) {
+    let params: PartialResultProgress = match params.parse() {
+        Ok(params) => params,
+        Err(_) => return,
+    };
+    if params.token != NumberOrString::String(WORKSPACE_DIAGNOSTIC_TOKEN.to_string()) {
+        return;
+    }
+    let meta = match &ctx.workspace_diagnostics_request {
+        Some((_, meta)) => meta.clone(),
+        None => return,
+    };
+    match serde_json::from_value(params.value) {
+        Ok(report) => editor_workspace_diagnostic(meta, report, ctx),
+        Err(e) => error!(
+            "Failed to parse workspace diagnostics partial result: {}",
+            e
+        ),
+    }
+}
+
+fn editor_workspace_diagnostic(
+    meta: EditorMeta,
+    report: WorkspaceDiagnosticReport,
+    ctx: &mut Context,
+) {
+    for item in report.items {
+        let (uri, report) = match item {
+            WorkspaceDocumentDiagnosticReport::Full(item) => {
+                (item.uri, DocumentDiagnosticReport::Full(item.report))
+            }
+            WorkspaceDocumentDiagnosticReport::Unchanged(item) => {
+                (item.uri, DocumentDiagnosticReport::Unchanged(item.report))
+            }
+        };
+        if let Some(buffile) = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| path.to_str().map(|buffile| buffile.to_string()))
+        {
+            editor_document_diagnostic(&buffile, report, ctx);
+        }
+    }
+    diagnostics::show_diagnostics(meta, ctx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_workspace_diagnostic_report() {
+        let report: WorkspaceDiagnosticReport = serde_json::from_value(json!({
+            "items": [
+                {
+                    "kind": "full",
+                    "uri": "file:///src/main.rs",
+                    "version": null,
+                    "resultId": "1",
+                    "items": [{
+                        "range": {
+                            "start": { "line": 1, "character": 0 },
+                            "end": { "line": 1, "character": 3 }
+                        },
+                        "message": "unused variable"
+                    }]
+                },
+                { "kind": "unchanged", "uri": "file:///src/lib.rs", "version": 3, "resultId": "2" }
+            ]
+        }))
+        .unwrap();
+        match &report.items[..] {
+            [WorkspaceDocumentDiagnosticReport::Full(full), WorkspaceDocumentDiagnosticReport::Unchanged(unchanged)] =>
+            {
+                assert_eq!(full.report.result_id.as_deref(), Some("1"));
+                assert_eq!(full.report.items[0].message, "unused variable");
+                assert_eq!(unchanged.version, Some(3));
+                assert_eq!(unchanged.report.result_id, "2");
+            }
+            items => panic!("unexpected items: {:?}", items),
+        }
+    }
+}
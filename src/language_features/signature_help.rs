@@ -1,15 +1,21 @@
 use crate::context::*;
+use crate::markup::*;
+use crate::position::*;
 use crate::types::*;
 use crate::util::*;
+use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use serde::Deserialize;
+use std::cmp::min;
 use url::Url;
 
+pub const FACE_INFO_ACTIVE_PARAMETER: &str = "InfoActiveParameter";
+
 pub fn text_document_signature_help(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = PositionParams::deserialize(params).unwrap();
     let req_params = SignatureHelpParams {
-        context: None,
+        context: Some(signature_help_context(&meta, &params.position, ctx)),
         text_document_position_params: TextDocumentPositionParams {
             text_document: TextDocumentIdentifier {
                 uri: Url::from_file_path(&meta.buffile).unwrap(),
@@ -31,18 +37,261 @@ pub fn editor_signature_help(
     result: Option<SignatureHelp>,
     ctx: &mut Context,
 ) {
-    if let Some(result) = result {
-        let active_signature = result.active_signature.unwrap_or(0);
-        if let Some(active_signature) = result.signatures.get(active_signature as usize) {
-            // TODO decide how to use it
-            // let active_parameter = result.active_parameter.unwrap_or(0);
-            let contents = &active_signature.label;
-            let command = format!(
-                "lsp-show-signature-help {} {}",
-                params.position,
-                editor_quote(contents)
-            );
-            ctx.exec(meta, command);
+    ctx.signature_help = result;
+    ctx.signature_help_buffile = meta.buffile.clone();
+    show_signature_help(meta, &params.position, ctx);
+}
+
+/// Tell the server whether signature help was triggered by one of its trigger characters, and
+/// whether it is a retrigger of the signature help that is still active in this buffer.
+fn signature_help_context(
+    meta: &EditorMeta,
+    position: &KakounePosition,
+    ctx: &Context,
+) -> SignatureHelpContext {
+    let active_signature_help = ctx
+        .signature_help
+        .as_ref()
+        .filter(|_| ctx.signature_help_buffile == meta.buffile)
+        .cloned();
+    let is_retrigger = active_signature_help.is_some();
+    let before_cursor = ctx.documents.get(&meta.buffile).map(|document| {
+        let line = get_line(position.line as usize - 1, &document.text).to_string();
+        let end = min(position.column as usize - 1, line.len());
+        line.get(..end).unwrap_or_default().to_string()
+    });
+    let (trigger_characters, retrigger_characters) = signature_help_trigger_characters(ctx);
+    let trigger_character = before_cursor.and_then(|before_cursor| {
+        trigger_characters
+            .iter()
+            .chain(retrigger_characters.iter().filter(|_| is_retrigger))
+            .find(|c| !c.is_empty() && before_cursor.ends_with(c.as_str()))
+            .cloned()
+    });
+    SignatureHelpContext {
+        trigger_kind: if trigger_character.is_some() {
+            SignatureHelpTriggerKind::TriggerCharacter
+        } else if is_retrigger {
+            SignatureHelpTriggerKind::ContentChange
+        } else {
+            SignatureHelpTriggerKind::Invoked
+        },
+        trigger_character,
+        is_retrigger,
+        active_signature_help,
+    }
+}
+
+fn signature_help_trigger_characters(ctx: &Context) -> (&[String], &[String]) {
+    let options = ctx
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.signature_help_provider.as_ref());
+    (
+        options
+            .and_then(|options| options.trigger_characters.as_deref())
+            .unwrap_or_default(),
+        options
+            .and_then(|options| options.retrigger_characters.as_deref())
+            .unwrap_or_default(),
+    )
+}
+
+/// Build the command to expose the server's signature help trigger and retrigger characters to
+/// the editor as buffer options.
+pub fn trigger_characters_command(buffile: &str, ctx: &Context) -> String {
+    let (trigger_characters, retrigger_characters) = signature_help_trigger_characters(ctx);
+    let quote_all = |characters: &[String]| characters.iter().map(|c| editor_quote(c)).join(" ");
+    format!(
+        "set-option buffer={buffile} lsp_signature_help_trigger_characters {}
+set-option buffer={buffile} lsp_signature_help_retrigger_characters {}",
+        quote_all(trigger_characters),
+        quote_all(retrigger_characters),
+        buffile = editor_quote(buffile)
+    )
+}
+
+/// Show the next or previous signature of the last signature help.
+pub fn signature_help_cycle(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
+    let params = SignatureHelpCycleParams::deserialize(params)
+        .expect("Params should follow SignatureHelpCycleParams structure");
+    let help = match ctx.signature_help.as_mut() {
+        Some(help) if !help.signatures.is_empty() => help,
+        _ => {
+            ctx.exec(meta, "lsp-show-error 'no signature help to cycle through'");
+            return;
+        }
+    };
+    let count = help.signatures.len() as i32;
+    let active = help.active_signature.unwrap_or(0) as i32;
+    help.active_signature = Some((active + params.step).rem_euclid(count) as u32);
+    show_signature_help(meta, &params.position, ctx);
+}
+
+/// End the signature help session once the user leaves insert mode, so that the next request is
+/// not a retrigger of the old one. The last signature help can still be cycled through.
+pub fn signature_help_end(ctx: &mut Context) {
+    ctx.signature_help_buffile.clear();
+}
+
+fn show_signature_help(meta: EditorMeta, position: &KakounePosition, ctx: &Context) {
+    if let Some(contents) = ctx.signature_help.as_ref().and_then(signature_help_markup) {
+        let command = format!(
+            "lsp-show-signature-help {} %§{}§",
+            position,
+            contents.replace("§", "\\§")
+        );
+        ctx.exec(meta, command);
+    }
+}
+
+/// Render the active signature with its active parameter highlighted, followed by the
+/// documentation of the parameter and of the signature.
+fn signature_help_markup(help: &SignatureHelp) -> Option<String> {
+    let active_signature = help.active_signature.unwrap_or(0) as usize;
+    let signature = help.signatures.get(active_signature)?;
+    let label = &signature.label;
+    let active_parameter = signature
+        .active_parameter
+        .or(help.active_parameter)
+        .unwrap_or(0) as usize;
+    let parameter = signature
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.get(active_parameter));
+    let range = signature
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameter_ranges(label, parameters).nth(active_parameter))
+        .flatten();
+
+    let mut markup = match range {
+        Some((start, end)) => format!(
+            "{}{{{}}}{}{{{}}}{}",
+            escape_brace(&label[..start]),
+            FACE_INFO_ACTIVE_PARAMETER,
+            escape_brace(&label[start..end]),
+            FACE_INFO_DEFAULT,
+            escape_brace(&label[end..])
+        ),
+        None => escape_brace(label),
+    };
+    if help.signatures.len() > 1 {
+        markup.push_str(&format!(
+            " ({}/{})",
+            active_signature + 1,
+            help.signatures.len()
+        ));
+    }
+
+    let docs = parameter
+        .and_then(|parameter| parameter.documentation.as_ref())
+        .into_iter()
+        .chain(signature.documentation.as_ref())
+        .map(documentation_to_kakoune_markup)
+        .filter(|doc| !doc.is_empty())
+        .collect::<Vec<_>>();
+    if !docs.is_empty() {
+        markup.push_str("\n\n");
+        markup.push_str(&docs.join("\n\n"));
+    }
+    Some(markup)
+}
+
+fn documentation_to_kakoune_markup(documentation: &Documentation) -> String {
+    match documentation {
+        Documentation::String(s) => markdown_to_kakoune_markup(s),
+        Documentation::MarkupContent(content) => match content.kind {
+            MarkupKind::Markdown => markdown_to_kakoune_markup(&content.value),
+            MarkupKind::PlainText => escape_brace(&content.value),
+        },
+    }
+}
+
+/// Byte ranges of the parameters in the signature label. Simple labels are searched for in order,
+/// starting after the opening parenthesis, so that a parameter is not found inside the function
+/// name or an earlier parameter.
+fn parameter_ranges<'a>(
+    label: &'a str,
+    parameters: &'a [ParameterInformation],
+) -> impl Iterator<Item = Option<(usize, usize)>> + 'a {
+    let mut search_start = label.find('(').map_or(0, |i| i + 1);
+    parameters
+        .iter()
+        .map(move |parameter| match &parameter.label {
+            ParameterLabel::Simple(s) if s.is_empty() => None,
+            ParameterLabel::Simple(s) => {
+                let start = search_start + label[search_start..].find(s.as_str())?;
+                search_start = start + s.len();
+                Some((start, search_start))
+            }
+            ParameterLabel::LabelOffsets([start, end]) => {
+                let start = utf_16_offset_to_byte(label, *start);
+                let end = utf_16_offset_to_byte(label, *end).max(start);
+                search_start = end;
+                Some((start, end))
+            }
+        })
+}
+
+fn utf_16_offset_to_byte(s: &str, offset: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in s.char_indices() {
+        if units >= offset as usize {
+            return i;
         }
+        units += c.len_utf16();
+    }
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameter(label: ParameterLabel) -> ParameterInformation {
+        ParameterInformation {
+            label,
+            documentation: None,
+        }
+    }
+
+    #[test]
+    fn utf_16_offset_to_byte_handles_multi_unit_characters() {
+        let s = "aé😀b";
+        assert_eq!(utf_16_offset_to_byte(s, 0), 0);
+        assert_eq!(utf_16_offset_to_byte(s, 1), 1);
+        assert_eq!(utf_16_offset_to_byte(s, 2), 3);
+        assert_eq!(utf_16_offset_to_byte(s, 4), 7);
+        assert_eq!(utf_16_offset_to_byte(s, 5), 8);
+        assert_eq!(utf_16_offset_to_byte(s, 42), 8);
+    }
+
+    #[test]
+    fn parameter_ranges_finds_simple_labels_after_the_function_name() {
+        let label = "x(x: é, y: u8) -> x";
+        let parameters = [
+            parameter(ParameterLabel::Simple("x: é".to_string())),
+            parameter(ParameterLabel::Simple(String::new())),
+            parameter(ParameterLabel::Simple("y: u8".to_string())),
+            parameter(ParameterLabel::Simple("z".to_string())),
+        ];
+        let ranges = parameter_ranges(label, &parameters).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![Some((2, 7)), None, Some((9, 14)), None]);
+        assert_eq!(&label[2..7], "x: é");
+        assert_eq!(&label[9..14], "y: u8");
+    }
+
+    #[test]
+    fn parameter_ranges_converts_label_offsets() {
+        let label = "f(é: u8, 😀: u8)";
+        let parameters = [
+            parameter(ParameterLabel::LabelOffsets([2, 7])),
+            parameter(ParameterLabel::LabelOffsets([9, 15])),
+        ];
+        let ranges = parameter_ranges(label, &parameters).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![Some((2, 8)), Some((10, 18))]);
+        assert_eq!(&label[2..8], "é: u8");
+        assert_eq!(&label[10..18], "😀: u8");
     }
 }
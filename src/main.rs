@@ -8,9 +8,11 @@ extern crate slog;
 #[macro_use]
 extern crate slog_scope;
 
+mod check;
 mod context;
 mod controller;
 mod diagnostics;
+mod diagnostics_export;
 mod editor_transport;
 mod general;
 mod language_features;
@@ -20,6 +22,7 @@ mod position;
 mod project_root;
 mod session;
 mod settings;
+mod snippet;
 mod text_edit;
 mod text_sync;
 mod thread_worker;
@@ -29,7 +32,7 @@ mod workspace;
 
 use crate::types::*;
 use crate::util::*;
-use clap::{crate_version, App, Arg, ArgMatches};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use daemonize::Daemonize;
 use itertools::Itertools;
 use sloggers::file::FileLoggerBuilder;
@@ -43,13 +46,15 @@ use std::io::{stdin, Read, Write};
 use std::os::unix::net::UnixStream;
 use std::panic;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
+use std::time::Duration;
 
 fn main() {
     let matches = App::new("kak-lsp")
         .version(crate_version!())
         .author("Ruslan Prokopchuk <fer.obbee@gmail.com>")
         .about("Kakoune Language Server Protocol Client")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("kakoune")
                 .long("kakoune")
@@ -109,6 +114,49 @@ fn main() {
                 .help("File to write the log into instead of stderr")
                 .takes_value(true),
         )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Run the language server over a project and print its diagnostics")
+                .arg(
+                    Arg::with_name("language")
+                        .long("language")
+                        .value_name("LANGUAGE")
+                        .help("Language to check, as configured in kak-lsp.toml")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("root")
+                        .long("root")
+                        .value_name("DIR")
+                        .help("Project root (default: current directory)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("glob")
+                        .long("glob")
+                        .value_name("PATTERN")
+                        .help("Files to check, relative to the project root (default: files of the language's filetypes)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Output format (default: text)")
+                        .possible_values(&["text", "json", "sarif", "checkstyle"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("TIMEOUT")
+                        .help("Maximum time to wait for diagnostics in seconds (default 300)")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
     if matches.is_present("kakoune") {
@@ -135,6 +183,11 @@ fn main() {
         config = fs::read_to_string(config_path).expect("Failed to read config");
     }
 
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let code = check(&config, &matches, check_matches);
+        process::exit(code);
+    }
+
     let session = String::from(matches.value_of("session").unwrap());
 
     let mut config: Config = match toml::from_str(&config) {
@@ -201,6 +254,51 @@ fn kakoune() {
     println!("{}\n{}", script, lsp_cmd);
 }
 
+fn check(config: &str, matches: &ArgMatches, check_matches: &ArgMatches) -> i32 {
+    let config: Config = match toml::from_str(config) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            eprintln!("kak-lsp: invalid configuration: {}", err);
+            return 2;
+        }
+    };
+    let _guard = setup_logger(&config, matches);
+    let root = check_matches.value_of("root").unwrap_or(".");
+    let root = match fs::canonicalize(root) {
+        Ok(root) => root,
+        Err(err) => {
+            eprintln!("kak-lsp: failed to access project root {}: {}", root, err);
+            return 2;
+        }
+    };
+    let globs = check_matches
+        .values_of("glob")
+        .map(|globs| globs.map(String::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let format = match check_matches.value_of("format") {
+        Some("json") => Some(DiagnosticsExportFormat::Json),
+        Some("sarif") => Some(DiagnosticsExportFormat::Sarif),
+        Some("checkstyle") => Some(DiagnosticsExportFormat::Checkstyle),
+        _ => None,
+    };
+    let timeout = match check_matches.value_of("timeout").map(str::parse) {
+        None => 300,
+        Some(Ok(timeout)) => timeout,
+        Some(Err(err)) => {
+            eprintln!("kak-lsp: invalid timeout: {}", err);
+            return 2;
+        }
+    };
+    check::start(
+        config,
+        check_matches.value_of("language").unwrap(),
+        root.to_str().unwrap(),
+        &globs,
+        format,
+        Duration::from_secs(timeout),
+    )
+}
+
 fn request(config: &Config) {
     let mut input = Vec::new();
     stdin()
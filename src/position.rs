@@ -27,7 +27,7 @@
 use crate::types::*;
 use lsp_types::*;
 use ropey::{Rope, RopeSlice};
-use std::cmp::min;
+use std::cmp::{max, min};
 
 pub const EOL_OFFSET: u32 = 1_000_000;
 
@@ -86,6 +86,156 @@ fn get_byte_index(char_index: usize, text: RopeSlice) -> usize {
     text.char_to_byte(min(char_index, text.len_chars()))
 }
 
+/// Convert LSP position to a char index into the text, clamping it to the end of line or text.
+pub fn lsp_position_to_char(
+    position: &Position,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    if position.line as usize >= text.len_lines() {
+        return text.len_chars();
+    }
+    let line_start = text.line_to_char(position.line as _);
+    let line = text.line(position.line as _);
+    match offset_encoding {
+        OffsetEncoding::Utf8 => {
+            let byte = min(position.character as usize, line.len_bytes());
+            line_start + line.byte_to_char(byte)
+        }
+        // Not a proper UTF-16 code units handling, but works within BMP
+        OffsetEncoding::Utf16 => line_start + min(position.character as usize, line.len_chars()),
+    }
+}
+
+/// Convert a char index into the text to LSP position.
+pub fn char_to_lsp_position(
+    char_index: usize,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
+    let char_index = min(char_index, text.len_chars());
+    let line = text.char_to_line(char_index);
+    let character = match offset_encoding {
+        OffsetEncoding::Utf8 => text.char_to_byte(char_index) - text.line_to_byte(line),
+        // Not a proper UTF-16 code units handling, but works within BMP
+        OffsetEncoding::Utf16 => char_index - text.line_to_char(line),
+    };
+    Position {
+        line: line as _,
+        character: character as _,
+    }
+}
+
+/// Convert Kakoune position to a char index into the text.
+pub fn kakoune_position_to_char(position: &KakounePosition, text: &Rope) -> usize {
+    let line = min(position.line as usize - 1, text.len_lines() - 1);
+    let line_start = text.line_to_byte(line);
+    let byte = min(
+        line_start + position.column as usize - 1,
+        line_start + text.line(line).len_bytes(),
+    );
+    text.byte_to_char(byte)
+}
+
+/// Convert a char index into the text to Kakoune position.
+pub fn char_to_kakoune_position(char_index: usize, text: &Rope) -> KakounePosition {
+    let char_index = min(char_index, text.len_chars());
+    let line = text.char_to_line(char_index);
+    // +1 because Rope indices are 0-based, but Kakoune's are 1-based.
+    KakounePosition {
+        line: line as u32 + 1,
+        column: (text.char_to_byte(char_index) - text.line_to_byte(line)) as u32 + 1,
+    }
+}
+
+/// Convert a Kakoune selection to an LSP range. Unlike Kakoune selections, LSP ranges exclude
+/// their end, so a selection of a single character is treated as a cursor and gives an empty range.
+pub fn kakoune_selection_to_lsp(
+    selection: &KakouneRange,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Range {
+    let anchor = kakoune_position_to_char(&selection.start, text);
+    let cursor = kakoune_position_to_char(&selection.end, text);
+    let (start, end) = if anchor == cursor {
+        (cursor, cursor)
+    } else {
+        (min(anchor, cursor), max(anchor, cursor) + 1)
+    };
+    Range {
+        start: char_to_lsp_position(start, text, offset_encoding),
+        end: char_to_lsp_position(end, text, offset_encoding),
+    }
+}
+
+/// The single contiguous region in which two versions of a text differ, in char indices.
+#[derive(Debug, PartialEq)]
+pub struct TextChange {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl TextChange {
+    /// Find the change between old and new text by stripping their common prefix and suffix.
+    /// Returns None if both texts are the same.
+    pub fn between(old: &Rope, new: &Rope) -> Option<Self> {
+        let prefix = old
+            .chars()
+            .zip(new.chars())
+            .take_while(|(a, b)| a == b)
+            .count();
+        if prefix == old.len_chars() && prefix == new.len_chars() {
+            return None;
+        }
+        let max_suffix = min(old.len_chars(), new.len_chars()) - prefix;
+        let mut old_chars = old.chars_at(old.len_chars());
+        let mut new_chars = new.chars_at(new.len_chars());
+        let mut suffix = 0;
+        while suffix < max_suffix {
+            match (old_chars.prev(), new_chars.prev()) {
+                (Some(a), Some(b)) if a == b => suffix += 1,
+                _ => break,
+            }
+        }
+        Some(TextChange {
+            start: prefix,
+            old_end: old.len_chars() - suffix,
+            new_end: new.len_chars() - suffix,
+        })
+    }
+
+    /// Map a char index into the old text to the new one. Indices inside the changed region are
+    /// moved to its start.
+    pub fn map(&self, char_index: usize) -> usize {
+        if char_index <= self.start {
+            char_index
+        } else if char_index >= self.old_end {
+            char_index - self.old_end + self.new_end
+        } else {
+            self.start
+        }
+    }
+
+    /// Whether a char index into the old text is strictly inside the changed region, so that
+    /// there is no telling where it went.
+    pub fn replaces(&self, char_index: usize) -> bool {
+        self.start < char_index && char_index < self.old_end
+    }
+
+    /// Map LSP position in the old text to the new one.
+    pub fn map_position(
+        &self,
+        position: &Position,
+        old: &Rope,
+        new: &Rope,
+        offset_encoding: OffsetEncoding,
+    ) -> Position {
+        let char_index = lsp_position_to_char(position, old, offset_encoding);
+        char_to_lsp_position(self.map(char_index), new, offset_encoding)
+    }
+}
+
 fn lsp_range_to_kakoune_utf_8_code_points(range: &Range, text: &Rope) -> KakouneRange {
     let Range { start, end } = range;
 
@@ -316,4 +466,63 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn text_change_maps_positions_after_the_change() {
+        let old = Rope::from_str("use a;\nfn f() { fo }\n");
+        let new = Rope::from_str("use a;\nfn f() { foo() }\n");
+        let change = TextChange::between(&old, &new).unwrap();
+        assert_eq!(
+            change,
+            TextChange {
+                start: 18,
+                old_end: 18,
+                new_end: 21
+            }
+        );
+        let position = |line, character| Position { line, character };
+        assert_eq!(
+            change.map_position(&position(0, 0), &old, &new, OffsetEncoding::Utf16),
+            position(0, 0)
+        );
+        assert_eq!(
+            change.map_position(&position(1, 12), &old, &new, OffsetEncoding::Utf16),
+            position(1, 15)
+        );
+        assert_eq!(
+            change.map_position(&position(2, 0), &old, &new, OffsetEncoding::Utf8),
+            position(2, 0)
+        );
+        assert_eq!(TextChange::between(&old, &old), None);
+    }
+
+    #[test]
+    fn kakoune_selection_to_lsp_excludes_end() {
+        let text = Rope::from_str("fn main() {\n    foo(1);\n}\n");
+        let range = |desc: &str| {
+            kakoune_selection_to_lsp(&desc.parse().unwrap(), &text, OffsetEncoding::Utf16)
+        };
+        let position = |line, character| Position { line, character };
+        assert_eq!(
+            range("2.5,2.7"),
+            Range {
+                start: position(1, 4),
+                end: position(1, 7)
+            }
+        );
+        assert_eq!(
+            range("2.7,1.4"),
+            Range {
+                start: position(0, 3),
+                end: position(1, 7)
+            }
+        );
+        assert_eq!(
+            range("2.5,2.5"),
+            Range {
+                start: position(1, 4),
+                end: position(1, 4)
+            }
+        );
+    }
 }
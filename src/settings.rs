@@ -7,6 +7,9 @@ pub fn request_dynamic_configuration_from_kakoune(
     meta: &EditorMeta,
     ctx: &mut Context,
 ) -> Option<DynamicLanguageConfig> {
+    if ctx.headless {
+        return None;
+    }
     let fifo = temp_fifo()?;
     ctx.exec(
         meta.clone(),
@@ -78,6 +81,9 @@ pub fn request_legacy_initialization_options_from_kakoune(
     meta: &EditorMeta,
     ctx: &mut Context,
 ) -> Option<Value> {
+    if ctx.headless {
+        return None;
+    }
     let fifo = temp_fifo()?;
     ctx.exec(
         meta.clone(),
@@ -0,0 +1,737 @@
+//! Parse and expand snippets in the LSP snippet syntax, see
+//! https://microsoft.github.io/language-server-protocol/specifications/specification-current/#snippet_syntax
+//!
+//! Snippets are expanded to plain text and a list of placeholder ranges, which kak-lsp turns into
+//! Kakoune selections. Malformed snippet syntax is inserted literally.
+use regex::{Captures, Regex, RegexBuilder};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug)]
+pub enum SnippetElement {
+    Text(String),
+    // $1, ${1} and ${1:placeholder}
+    Tabstop {
+        number: usize,
+        placeholder: Vec<SnippetElement>,
+    },
+    // ${1|one,two,three|}
+    Choice {
+        number: usize,
+        options: Vec<String>,
+    },
+    // $name, ${name}, ${name:default} and ${name/regex/format/options}
+    Variable {
+        name: String,
+        default: Vec<SnippetElement>,
+        transform: Option<Transform>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Transform {
+    regex: Regex,
+    format: Vec<FormatElement>,
+    global: bool,
+}
+
+#[derive(Debug)]
+enum FormatElement {
+    Text(String),
+    // $1 and ${1}
+    Group(usize),
+    // ${1:/upcase}, ${1:/downcase} and ${1:/capitalize}
+    Case(usize, Case),
+    // ${1:+if}, ${1:?if:else}, ${1:-else} and ${1:else}
+    Conditional {
+        group: usize,
+        if_matched: String,
+        otherwise: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Case {
+    Upcase,
+    Downcase,
+    Capitalize,
+}
+
+/// Range of a placeholder in the expanded text, in char offsets.
+#[derive(Debug, PartialEq)]
+pub struct Placeholder {
+    pub tabstop: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExpandedSnippet {
+    pub text: String,
+    // Placeholders in the order they appear in the text.
+    pub placeholders: Vec<Placeholder>,
+}
+
+impl ExpandedSnippet {
+    /// Tabstop numbers in the order they are visited, i.e. ascending with the final tabstop $0
+    /// last.
+    pub fn tabstops(&self) -> Vec<usize> {
+        let mut tabstops = self
+            .placeholders
+            .iter()
+            .map(|p| p.tabstop)
+            .collect::<Vec<_>>();
+        tabstops.sort_by_key(|&tabstop| (tabstop == 0, tabstop));
+        tabstops.dedup();
+        tabstops
+    }
+}
+
+pub fn parse_snippet(snippet: &str) -> Vec<SnippetElement> {
+    let mut parser = Parser {
+        chars: snippet.chars().collect(),
+        pos: 0,
+        failed: HashSet::new(),
+    };
+    parser.parse_any(&[])
+}
+
+/// Expand a snippet to plain text.
+///
+/// Newlines in the snippet are followed by `indent`, and tabs at the start of a line are replaced
+/// with `tab`. `variables` resolves variables such as TM_FILENAME, and returns None for unknown
+/// ones.
+pub fn expand_snippet(
+    snippet: &str,
+    indent: &str,
+    tab: &str,
+    variables: &dyn Fn(&str) -> Option<String>,
+) -> ExpandedSnippet {
+    let elements = parse_snippet(snippet);
+    // Tabstops without a placeholder of their own mirror the placeholder given elsewhere.
+    let mut defaults = HashMap::new();
+    collect_defaults(&elements, variables, &mut defaults);
+    let mut expander = Expander {
+        text: String::new(),
+        len: 0,
+        at_line_start: false,
+        indent,
+        tab,
+        placeholders: Vec::new(),
+        defaults,
+        variables,
+    };
+    expander.expand(&elements);
+    ExpandedSnippet {
+        text: expander.text,
+        placeholders: expander.placeholders,
+    }
+}
+
+fn collect_defaults(
+    elements: &[SnippetElement],
+    variables: &dyn Fn(&str) -> Option<String>,
+    defaults: &mut HashMap<usize, String>,
+) {
+    for element in elements {
+        match element {
+            SnippetElement::Tabstop {
+                number,
+                placeholder,
+            } if !placeholder.is_empty() => {
+                collect_defaults(placeholder, variables, defaults);
+                if !defaults.contains_key(number) {
+                    let mut expander = Expander {
+                        text: String::new(),
+                        len: 0,
+                        at_line_start: false,
+                        indent: "",
+                        tab: "\t",
+                        placeholders: Vec::new(),
+                        defaults: HashMap::new(),
+                        variables,
+                    };
+                    expander.expand(placeholder);
+                    defaults.insert(*number, expander.text);
+                }
+            }
+            SnippetElement::Choice { number, options } => {
+                if let Some(first) = options.first() {
+                    defaults.entry(*number).or_insert_with(|| first.clone());
+                }
+            }
+            SnippetElement::Variable { default, .. } => {
+                collect_defaults(default, variables, defaults)
+            }
+            _ => (),
+        }
+    }
+}
+
+struct Expander<'a> {
+    text: String,
+    // Length of text in chars.
+    len: usize,
+    at_line_start: bool,
+    indent: &'a str,
+    tab: &'a str,
+    placeholders: Vec<Placeholder>,
+    defaults: HashMap<usize, String>,
+    variables: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> Expander<'a> {
+    fn push_str(&mut self, s: &str) {
+        for c in s.chars() {
+            match c {
+                '\n' => {
+                    self.text.push('\n');
+                    self.text.push_str(self.indent);
+                    self.len += 1 + self.indent.chars().count();
+                    self.at_line_start = true;
+                }
+                '\t' if self.at_line_start => {
+                    self.text.push_str(self.tab);
+                    self.len += self.tab.chars().count();
+                }
+                c => {
+                    self.text.push(c);
+                    self.len += 1;
+                    self.at_line_start = false;
+                }
+            }
+        }
+    }
+
+    fn expand(&mut self, elements: &[SnippetElement]) {
+        for element in elements {
+            match element {
+                SnippetElement::Text(text) => self.push_str(text),
+                SnippetElement::Tabstop {
+                    number,
+                    placeholder,
+                } => {
+                    let start = self.len;
+                    if placeholder.is_empty() {
+                        if let Some(default) = self.defaults.get(number).cloned() {
+                            self.push_str(&default);
+                        }
+                    } else {
+                        self.expand(placeholder);
+                    }
+                    self.placeholders.push(Placeholder {
+                        tabstop: *number,
+                        start,
+                        end: self.len,
+                    });
+                }
+                SnippetElement::Choice { number, options } => {
+                    let start = self.len;
+                    if let Some(first) = options.first() {
+                        self.push_str(first);
+                    }
+                    self.placeholders.push(Placeholder {
+                        tabstop: *number,
+                        start,
+                        end: self.len,
+                    });
+                }
+                SnippetElement::Variable {
+                    name,
+                    default,
+                    transform,
+                } => match (self.variables)(name) {
+                    Some(value) if !value.is_empty() => {
+                        let value = match transform {
+                            Some(transform) => transform.apply(&value),
+                            None => value,
+                        };
+                        self.push_str(&value);
+                    }
+                    // Empty variables are replaced by their default.
+                    Some(_) => self.expand(default),
+                    // Unknown variables are replaced by their default or else their name.
+                    None if default.is_empty() => self.push_str(name),
+                    None => self.expand(default),
+                },
+            }
+        }
+    }
+}
+
+impl Transform {
+    fn apply(&self, value: &str) -> String {
+        let replace = |captures: &Captures| {
+            let group = |n: usize| captures.get(n).map(|m| m.as_str()).unwrap_or("");
+            let mut result = String::new();
+            for element in &self.format {
+                match element {
+                    FormatElement::Text(text) => result.push_str(text),
+                    FormatElement::Group(n) => result.push_str(group(*n)),
+                    FormatElement::Case(n, case) => result.push_str(&apply_case(group(*n), *case)),
+                    FormatElement::Conditional {
+                        group: n,
+                        if_matched,
+                        otherwise,
+                    } => {
+                        if group(*n).is_empty() {
+                            result.push_str(otherwise)
+                        } else {
+                            result.push_str(if_matched)
+                        }
+                    }
+                }
+            }
+            result
+        };
+        if self.global {
+            self.regex.replace_all(value, replace).into_owned()
+        } else {
+            self.regex.replace(value, replace).into_owned()
+        }
+    }
+}
+
+fn apply_case(s: &str, case: Case) -> String {
+    match case {
+        Case::Upcase => s.to_uppercase(),
+        Case::Downcase => s.to_lowercase(),
+        Case::Capitalize => {
+            let mut chars = s.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // Positions of dollars that are known not to start valid snippet syntax. Without them, nested
+    // unclosed placeholders would be parsed again at every level, which takes exponential time.
+    failed: HashSet<usize>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Parse elements until one of the `stop` characters or the end of input.
+    fn parse_any(&mut self, stop: &[char]) -> Vec<SnippetElement> {
+        let mut elements = Vec::new();
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if stop.contains(&c) {
+                break;
+            }
+            if c == '$' {
+                let start = self.pos;
+                if !self.failed.contains(&start) {
+                    if let Some(element) = self.parse_dollar() {
+                        if !text.is_empty() {
+                            elements.push(SnippetElement::Text(std::mem::take(&mut text)));
+                        }
+                        elements.push(element);
+                        continue;
+                    }
+                    self.failed.insert(start);
+                }
+                // Not valid snippet syntax, so take the dollar literally.
+                self.pos = start + 1;
+                text.push('$');
+                continue;
+            }
+            self.pos += 1;
+            if c == '\\' {
+                match self.peek() {
+                    Some(escaped @ ('$' | '}' | '\\')) => {
+                        self.pos += 1;
+                        text.push(escaped);
+                    }
+                    _ => text.push('\\'),
+                }
+            } else {
+                text.push(c);
+            }
+        }
+        if !text.is_empty() {
+            elements.push(SnippetElement::Text(text));
+        }
+        elements
+    }
+
+    fn parse_int(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn parse_var(&mut self) -> Option<String> {
+        let start = self.pos;
+        match self.peek() {
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => self.pos += 1,
+            _ => return None,
+        }
+        while matches!(self.peek(), Some(c) if c == '_' || c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        Some(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_dollar(&mut self) -> Option<SnippetElement> {
+        if !self.eat('$') {
+            return None;
+        }
+        if !self.eat('{') {
+            if let Some(number) = self.parse_int() {
+                return Some(SnippetElement::Tabstop {
+                    number,
+                    placeholder: Vec::new(),
+                });
+            }
+            return self.parse_var().map(|name| SnippetElement::Variable {
+                name,
+                default: Vec::new(),
+                transform: None,
+            });
+        }
+        if let Some(number) = self.parse_int() {
+            if self.eat('}') {
+                return Some(SnippetElement::Tabstop {
+                    number,
+                    placeholder: Vec::new(),
+                });
+            }
+            if self.eat(':') {
+                let placeholder = self.parse_any(&['}']);
+                return if self.eat('}') {
+                    Some(SnippetElement::Tabstop {
+                        number,
+                        placeholder,
+                    })
+                } else {
+                    None
+                };
+            }
+            if self.eat('|') {
+                let options = self.parse_choice_options()?;
+                return Some(SnippetElement::Choice { number, options });
+            }
+            if self.peek() == Some('/') {
+                // Transforms of tabstops only apply to their mirrors, which are not supported.
+                self.parse_transform()?;
+                return if self.eat('}') {
+                    Some(SnippetElement::Tabstop {
+                        number,
+                        placeholder: Vec::new(),
+                    })
+                } else {
+                    None
+                };
+            }
+            return None;
+        }
+        let name = self.parse_var()?;
+        if self.eat('}') {
+            return Some(SnippetElement::Variable {
+                name,
+                default: Vec::new(),
+                transform: None,
+            });
+        }
+        if self.eat(':') {
+            let default = self.parse_any(&['}']);
+            return if self.eat('}') {
+                Some(SnippetElement::Variable {
+                    name,
+                    default,
+                    transform: None,
+                })
+            } else {
+                None
+            };
+        }
+        if self.peek() == Some('/') {
+            let transform = self.parse_transform()?;
+            return if self.eat('}') {
+                Some(SnippetElement::Variable {
+                    name,
+                    default: Vec::new(),
+                    transform: Some(transform),
+                })
+            } else {
+                None
+            };
+        }
+        None
+    }
+
+    /// Parse the options of a choice after the opening '|', up to and including the closing '|}'.
+    fn parse_choice_options(&mut self) -> Option<Vec<String>> {
+        let mut options = Vec::new();
+        let mut option = String::new();
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            match c {
+                '\\' => match self.peek() {
+                    Some(escaped @ ('$' | '}' | '\\' | ',' | '|')) => {
+                        self.pos += 1;
+                        option.push(escaped);
+                    }
+                    _ => option.push('\\'),
+                },
+                ',' => options.push(std::mem::take(&mut option)),
+                '|' => {
+                    options.push(option);
+                    return if self.eat('}') { Some(options) } else { None };
+                }
+                c => option.push(c),
+            }
+        }
+    }
+
+    /// Read up to the next unescaped `/`, keeping escapes other than `\/` as they are.
+    fn parse_until_slash(&mut self) -> Option<String> {
+        let mut s = String::new();
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            match c {
+                '/' => return Some(s),
+                '\\' if self.peek() == Some('/') => {
+                    self.pos += 1;
+                    s.push('/');
+                }
+                '\\' => {
+                    s.push('\\');
+                    s.push(self.peek()?);
+                    self.pos += 1;
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_transform(&mut self) -> Option<Transform> {
+        if !self.eat('/') {
+            return None;
+        }
+        let regex = self.parse_until_slash()?;
+        let format = self.parse_format()?;
+        let mut options = String::new();
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphabetic()) {
+            options.push(self.peek().unwrap());
+            self.pos += 1;
+        }
+        let regex = RegexBuilder::new(&regex)
+            .case_insensitive(options.contains('i'))
+            .multi_line(options.contains('m'))
+            .build()
+            .ok()?;
+        Some(Transform {
+            regex,
+            format,
+            global: options.contains('g'),
+        })
+    }
+
+    /// Parse a transform's format string up to and including the closing '/'.
+    fn parse_format(&mut self) -> Option<Vec<FormatElement>> {
+        let mut elements = Vec::new();
+        let mut text = String::new();
+        loop {
+            let c = self.peek()?;
+            if c == '/' {
+                self.pos += 1;
+                break;
+            }
+            if c == '$' {
+                let start = self.pos;
+                if let Some(element) = self.parse_format_dollar() {
+                    if !text.is_empty() {
+                        elements.push(FormatElement::Text(std::mem::take(&mut text)));
+                    }
+                    elements.push(element);
+                    continue;
+                }
+                self.pos = start;
+            }
+            self.pos += 1;
+            if c == '\\' {
+                match self.peek() {
+                    Some(escaped @ ('$' | '}' | '\\' | '/')) => {
+                        self.pos += 1;
+                        text.push(escaped);
+                    }
+                    _ => text.push('\\'),
+                }
+            } else {
+                text.push(c);
+            }
+        }
+        if !text.is_empty() {
+            elements.push(FormatElement::Text(text));
+        }
+        Some(elements)
+    }
+
+    fn parse_format_dollar(&mut self) -> Option<FormatElement> {
+        if !self.eat('$') {
+            return None;
+        }
+        if !self.eat('{') {
+            return self.parse_int().map(FormatElement::Group);
+        }
+        let group = self.parse_int()?;
+        if self.eat('}') {
+            return Some(FormatElement::Group(group));
+        }
+        if !self.eat(':') {
+            return None;
+        }
+        let element = if self.eat('/') {
+            let case = match self.parse_var()?.as_str() {
+                "upcase" => Case::Upcase,
+                "downcase" => Case::Downcase,
+                "capitalize" => Case::Capitalize,
+                _ => return None,
+            };
+            FormatElement::Case(group, case)
+        } else if self.eat('+') {
+            FormatElement::Conditional {
+                group,
+                if_matched: self.parse_format_text(&['}'])?,
+                otherwise: String::new(),
+            }
+        } else if self.eat('?') {
+            let if_matched = self.parse_format_text(&[':'])?;
+            self.eat(':');
+            FormatElement::Conditional {
+                group,
+                if_matched,
+                otherwise: self.parse_format_text(&['}'])?,
+            }
+        } else {
+            self.eat('-');
+            FormatElement::Conditional {
+                group,
+                if_matched: String::new(),
+                otherwise: self.parse_format_text(&['}'])?,
+            }
+        };
+        if self.eat('}') {
+            Some(element)
+        } else {
+            None
+        }
+    }
+
+    /// Read text inside a format element up to one of the `stop` characters.
+    fn parse_format_text(&mut self, stop: &[char]) -> Option<String> {
+        let mut text = String::new();
+        loop {
+            let c = self.peek()?;
+            if stop.contains(&c) {
+                return Some(text);
+            }
+            self.pos += 1;
+            if c == '\\' {
+                text.push(self.peek()?);
+                self.pos += 1;
+            } else {
+                text.push(c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(snippet: &str) -> ExpandedSnippet {
+        expand_snippet(snippet, "    ", "  ", &|name| match name {
+            "TM_FILENAME" => Some("main.rs".to_string()),
+            "TM_SELECTED_TEXT" => Some(String::new()),
+            _ => None,
+        })
+    }
+
+    fn placeholder(tabstop: usize, start: usize, end: usize) -> Placeholder {
+        Placeholder {
+            tabstop,
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn expand_tabstops_placeholders_and_choices() {
+        let expanded = expand("foo(${1:a}, ${2|x,y|}, $1)$0");
+        assert_eq!(expanded.text, "foo(a, x, a)");
+        assert_eq!(
+            expanded.placeholders,
+            vec![
+                placeholder(1, 4, 5),
+                placeholder(2, 7, 8),
+                placeholder(1, 10, 11),
+                placeholder(0, 12, 12),
+            ]
+        );
+        assert_eq!(expanded.tabstops(), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn expand_nested_placeholders_and_indentation() {
+        let expanded = expand("if ${1:x == ${2:y}} {\n\t$0\n}");
+        assert_eq!(expanded.text, "if x == y {\n      \n    }");
+        assert_eq!(
+            expanded.placeholders,
+            vec![
+                placeholder(2, 8, 9),
+                placeholder(1, 3, 9),
+                placeholder(0, 18, 18),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_variables_and_transforms() {
+        assert_eq!(
+            expand("${TM_FILENAME/(\\w+)\\.rs/${1:/upcase}/} ${TM_SELECTED_TEXT:sel} $UNKNOWN")
+                .text,
+            "MAIN sel UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn expand_malformed_snippet_literally() {
+        let expanded = expand("a \\$1 ${1:b $2 ${");
+        assert_eq!(expanded.text, "a $1 ${1:b  ${");
+        assert_eq!(expanded.placeholders, vec![placeholder(2, 11, 11)]);
+    }
+
+    #[test]
+    fn parse_deeply_nested_unclosed_placeholders() {
+        let snippet = "${1:".repeat(100);
+        assert_eq!(expand(&snippet).text, snippet);
+    }
+}
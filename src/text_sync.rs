@@ -1,11 +1,21 @@
 use crate::context::*;
+use crate::language_features::{codeaction, completion, pull_diagnostics, signature_help};
+use crate::position::{char_to_lsp_position, lsp_position_to_char, TextChange};
+use crate::text_edit::apply_text_edits_to_buffer;
 use crate::types::*;
+use crate::util::*;
 use lsp_types::notification::*;
+use lsp_types::request::*;
 use lsp_types::*;
 use ropey::Rope;
 use serde::Deserialize;
+use std::time::{Duration, Instant};
 use url::Url;
 
+/// How long the editor waits for the edits to make before save, from `textDocument/willSaveWaitUntil`
+/// or code actions on save, before writing the buffer without them.
+const PRE_SAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
     let params = TextDocumentDidOpenParams::deserialize(params)
         .expect("Params should follow TextDocumentDidOpenParams structure");
@@ -22,8 +32,20 @@ pub fn text_document_did_open(meta: EditorMeta, params: EditorParams, ctx: &mut
         version: meta.version,
         text: Rope::from_str(&params.text_document.text),
     };
-    ctx.documents.insert(meta.buffile, document);
+    ctx.documents.insert(meta.buffile.clone(), document);
     ctx.notify::<DidOpenTextDocument>(params);
+    let command = format!(
+        "{}\n{}\n{}\nset-option buffer={} lsp_will_save_wait_until {}",
+        completion::trigger_characters_command(&meta.buffile, ctx),
+        signature_help::trigger_characters_command(&meta.buffile, ctx),
+        codeaction::code_actions_on_save_command(&meta.buffile, ctx),
+        editor_quote(&meta.buffile),
+        text_document_sync_options(ctx)
+            .and_then(|options| options.will_save_wait_until)
+            .unwrap_or(false)
+    );
+    ctx.exec(meta.clone(), command);
+    pull_diagnostics::text_document_diagnostic(meta, ctx);
 }
 
 pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mut Context) {
@@ -43,8 +65,11 @@ pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mu
         version,
         text: Rope::from_str(&params.draft),
     };
-    ctx.documents.insert(meta.buffile.clone(), document);
-    ctx.diagnostics.insert(meta.buffile.clone(), Vec::new());
+    // Cloning a rope is cheap, it shares the text.
+    let text = document.text.clone();
+    if let Some(old_document) = ctx.documents.insert(meta.buffile.clone(), document) {
+        shift_diagnostics(&meta.buffile, &old_document.text, &text, ctx);
+    }
     let params = DidChangeTextDocumentParams {
         text_document: VersionedTextDocumentIdentifier {
             uri,
@@ -57,10 +82,55 @@ pub fn text_document_did_change(meta: EditorMeta, params: EditorParams, ctx: &mu
         }],
     };
     ctx.notify::<DidChangeTextDocument>(params);
+    pull_diagnostics::text_document_diagnostic(meta, ctx);
+}
+
+/// Move the stored diagnostics of a buffer through an edit, so that they stay valid until the
+/// server sends new ones. Kakoune moves the highlighters itself.
+fn shift_diagnostics(buffile: &str, old: &Rope, new: &Rope, ctx: &mut Context) {
+    let offset_encoding = ctx.offset_encoding;
+    match ctx.diagnostics.get_mut(buffile) {
+        Some(diagnostics) if !diagnostics.is_empty() => {
+            shift_ranges(diagnostics, old, new, offset_encoding)
+        }
+        _ => (),
+    }
+}
+
+/// Move the diagnostic ranges of the old text to where the same text is in the new one. The
+/// change is a single span covering all edits, such as those made with multiple selections, so
+/// diagnostics inside it are kept in place rather than collapsed onto its start.
+fn shift_ranges(
+    diagnostics: &mut [Diagnostic],
+    old: &Rope,
+    new: &Rope,
+    offset_encoding: OffsetEncoding,
+) {
+    let change = match TextChange::between(old, new) {
+        Some(change) => change,
+        None => return,
+    };
+    for diagnostic in diagnostics {
+        let start = lsp_position_to_char(&diagnostic.range.start, old, offset_encoding);
+        let end = lsp_position_to_char(&diagnostic.range.end, old, offset_encoding);
+        if change.replaces(start) || change.replaces(end) {
+            continue;
+        }
+        diagnostic.range = Range {
+            start: char_to_lsp_position(change.map(start), new, offset_encoding),
+            end: char_to_lsp_position(change.map(end), new, offset_encoding),
+        };
+    }
 }
 
 pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
     ctx.documents.remove(&meta.buffile);
+    ctx.pulled_diagnostics.remove(&meta.buffile);
+    if let Some(id) = ctx.document_diagnostics_requests.remove(&meta.buffile) {
+        ctx.cancel(id);
+    }
+    ctx.diagnostics_to_publish.remove(&meta.buffile);
+    ctx.published_diagnostics.remove(&meta.buffile);
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let params = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
@@ -68,11 +138,198 @@ pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
     ctx.notify::<DidCloseTextDocument>(params);
 }
 
+fn text_document_sync_options(ctx: &Context) -> Option<&TextDocumentSyncOptions> {
+    match ctx.capabilities.as_ref()?.text_document_sync.as_ref()? {
+        TextDocumentSyncCapability::Options(options) => Some(options),
+        TextDocumentSyncCapability::Kind(_) => None,
+    }
+}
+
+pub fn text_document_will_save(meta: EditorMeta, ctx: &mut Context) {
+    if !text_document_sync_options(ctx)
+        .and_then(|options| options.will_save)
+        .unwrap_or(false)
+    {
+        return;
+    }
+    let params = WillSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        reason: TextDocumentSaveReason::Manual,
+    };
+    ctx.notify::<WillSaveTextDocument>(params);
+}
+
+/// Send `textDocument/willSave` and apply the edits returned by `textDocument/willSaveWaitUntil`.
+pub fn text_document_will_save_wait_until(meta: EditorMeta, ctx: &mut Context) {
+    text_document_will_save(meta.clone(), ctx);
+    if !text_document_sync_options(ctx)
+        .and_then(|options| options.will_save_wait_until)
+        .unwrap_or(false)
+    {
+        ctx.exec(meta, "nop");
+        return;
+    }
+
+    let params = WillSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier {
+            uri: Url::from_file_path(&meta.buffile).unwrap(),
+        },
+        reason: TextDocumentSaveReason::Manual,
+    };
+    let meta = begin_pre_save(meta, ctx);
+    ctx.batch_call_or_else::<WillSaveWaitUntil, _, _>(
+        meta,
+        vec![params],
+        move |ctx: &mut Context, meta, mut results| {
+            let text_edits = results
+                .pop()
+                .flatten()
+                .unwrap_or_default()
+                .into_iter()
+                .map(OneOf::Left)
+                .collect::<Vec<_>>();
+            let cmd = ctx.documents.get(&meta.buffile).and_then(|document| {
+                apply_text_edits_to_buffer(
+                    &meta.client,
+                    None,
+                    &text_edits,
+                    &document.text,
+                    ctx.offset_encoding,
+                )
+            });
+            // Nothing to do, but sending command back to the editor is required to handle case
+            // when editor is blocked waiting for response via fifo.
+            finish_pre_save(ctx, cmd.unwrap_or_else(|| "nop".to_string()));
+        },
+        |ctx: &mut Context, _| finish_pre_save(ctx, "nop".to_string()),
+    );
+}
+
+/// Start computing the edits to make before the editor writes a buffer. The editor is blocked on
+/// the fifo of `meta` until `finish_pre_save` is called, or until `pre_save_timeout` gives up on
+/// the language server. Returns the meta to send the requests with, which has no fifo, so that
+/// the editor gets exactly one command whatever the server answers and when.
+pub fn begin_pre_save(meta: EditorMeta, ctx: &mut Context) -> EditorMeta {
+    // The editor can't be blocked twice, but answer the old fifo in case it is still open.
+    finish_pre_save(ctx, "nop".to_string());
+    let request_meta = EditorMeta {
+        fifo: None,
+        ..meta.clone()
+    };
+    ctx.pre_save = Some((meta, Instant::now() + PRE_SAVE_TIMEOUT));
+    request_meta
+}
+
+/// Send the edits to make before save to the editor, unless it already stopped waiting for them.
+pub fn finish_pre_save(ctx: &mut Context, command: String) {
+    if let Some((meta, _)) = ctx.pre_save.take() {
+        ctx.exec(meta, command);
+    }
+}
+
+/// Unblock the editor if the language server hasn't computed the edits before save in time. The
+/// late edits are dropped.
+pub fn pre_save_timeout(ctx: &mut Context) {
+    if ctx.pre_save.is_some() {
+        warn!("Timed out waiting for edits before save");
+        finish_pre_save(
+            ctx,
+            "lsp-show-error 'timed out waiting for language server edits before save'".to_string(),
+        );
+    }
+}
+
 pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
+    pull_diagnostics::text_document_diagnostic_on_save(meta.clone(), ctx);
+    let include_text =
+        match text_document_sync_options(ctx).and_then(|options| options.save.as_ref()) {
+            Some(TextDocumentSyncSaveOptions::Supported(true)) => false,
+            Some(TextDocumentSyncSaveOptions::SaveOptions(options)) => {
+                options.include_text.unwrap_or(false)
+            }
+            // The server didn't ask for save notifications.
+            _ => return,
+        };
+    let text = if include_text {
+        ctx.documents
+            .get(&meta.buffile)
+            .map(|document| document.text.to_string())
+    } else {
+        None
+    };
     let uri = Url::from_file_path(&meta.buffile).unwrap();
     let params = DidSaveTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
-        text: None,
+        text,
     };
     ctx.notify::<DidSaveTextDocument>(params);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shifted(new: &str) -> Range {
+        let old = Rope::from_str("let x = foo(bar);\n");
+        let mut diagnostics = vec![Diagnostic {
+            range: Range::new(Position::new(0, 8), Position::new(0, 16)),
+            ..Diagnostic::default()
+        }];
+        shift_ranges(
+            &mut diagnostics,
+            &old,
+            &Rope::from_str(new),
+            OffsetEncoding::Utf16,
+        );
+        diagnostics[0].range
+    }
+
+    #[test]
+    fn shift_ranges_through_edits_at_multiple_sites() {
+        let old = Rope::from_str("a(x);\nb(y);\nc(z);\nd(w);\n");
+        let range = |line| Range::new(Position::new(line, 2), Position::new(line, 3));
+        let mut diagnostics = (0..4)
+            .map(|line| Diagnostic {
+                range: range(line),
+                ..Diagnostic::default()
+            })
+            .collect::<Vec<_>>();
+        // New lines inserted before b and d with two selections. The diagnostics between the
+        // edits can't be told apart from the edited text, so they stay where they were.
+        shift_ranges(
+            &mut diagnostics,
+            &old,
+            &Rope::from_str("a(x);\n\nb(y);\nc(z);\n\nd(w);\n"),
+            OffsetEncoding::Utf16,
+        );
+        assert_eq!(
+            diagnostics.iter().map(|d| d.range).collect::<Vec<_>>(),
+            vec![range(0), range(1), range(2), range(5)]
+        );
+    }
+
+    #[test]
+    fn shift_ranges_through_insertions() {
+        // Before the range.
+        assert_eq!(
+            shifted("let mut x = foo(bar);\n"),
+            Range::new(Position::new(0, 12), Position::new(0, 20))
+        );
+        assert_eq!(
+            shifted("\nlet x = foo(bar);\n"),
+            Range::new(Position::new(1, 8), Position::new(1, 16))
+        );
+        // Inside the range.
+        assert_eq!(
+            shifted("let x = foo(bar, baz);\n"),
+            Range::new(Position::new(0, 8), Position::new(0, 21))
+        );
+        // After the range.
+        assert_eq!(
+            shifted("let x = foo(bar); // ü\n"),
+            Range::new(Position::new(0, 8), Position::new(0, 16))
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use jsonrpc_core::{Call, Output, Params};
 use lsp_types::{DiagnosticSeverity, Position, Range, SemanticTokenModifier};
+use regex::Regex;
 use serde::{de::Error as SerdeError, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io::Error;
+use std::str::FromStr;
 
 pub enum Void {}
 
@@ -20,6 +22,8 @@ pub struct Config {
     pub verbosity: u8,
     #[serde(default)]
     pub snippet_support: bool,
+    #[serde(default = "default_completion_limit")]
+    pub completion_limit: usize,
     #[serde(default, deserialize_with = "deserialize_semantic_tokens")]
     pub semantic_tokens: Vec<SemanticTokenConfig>,
 }
@@ -50,6 +54,41 @@ pub struct LanguageConfig {
     pub settings: Option<Value>,
     #[serde(default = "default_offset_encoding")]
     pub offset_encoding: OffsetEncoding,
+    #[serde(default)]
+    pub code_actions_on_save: Vec<String>,
+    #[serde(default)]
+    pub diagnostic_rules: Vec<DiagnosticRule>,
+}
+
+/// Changes the severity of the diagnostics it matches, or hides them. A diagnostic matches if it
+/// has the given source and code, and its message matches the regex; fields that are not given
+/// match any diagnostic.
+#[derive(Clone, Deserialize, Debug)]
+pub struct DiagnosticRule {
+    pub source: Option<String>,
+    pub code: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub message: Option<Regex>,
+    pub severity: DiagnosticRuleSeverity,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticRuleSeverity {
+    Error,
+    Warning,
+    Info,
+    Hint,
+    Hide,
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Regex::new(&s).map_err(D::Error::custom))
+        .transpose()
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -66,6 +105,10 @@ impl Default for ServerConfig {
     }
 }
 
+fn default_completion_limit() -> usize {
+    100
+}
+
 fn default_offset_encoding() -> OffsetEncoding {
     OffsetEncoding::Utf16
 }
@@ -132,6 +175,9 @@ pub struct Route {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EditorCompletion {
     pub offset: u32,
+    // Kakoune's indentwidth option, used to indent snippets.
+    #[serde(default)]
+    pub indent_width: usize,
 }
 
 #[derive(Deserialize, Debug)]
@@ -150,11 +196,61 @@ pub struct TextDocumentCompletionParams {
     pub completion: EditorCompletion,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct CompletionItemResolveParams {
+    pub index: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletionItemAcceptParams {
+    pub index: usize,
+    // Cursor position after the candidate was inserted.
+    pub position: KakounePosition,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PositionParams {
     pub position: KakounePosition,
 }
 
+#[derive(Clone, Copy, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticsExportFormat {
+    // One JSON object per line.
+    Json,
+    // SARIF 2.1.0 log.
+    Sarif,
+    // Checkstyle XML report.
+    Checkstyle,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticsExportParams {
+    pub format: DiagnosticsExportFormat,
+    // File to write to, a scratch buffer is shown if missing.
+    pub output: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodeActionsParams {
+    // Descriptions of the selections to request code actions for.
+    pub selections_desc: Vec<String>,
+    // Code action kinds to request, all kinds if empty.
+    #[serde(default)]
+    pub only: Vec<String>,
+    // Apply the preferred quickfix for the diagnostic under the cursor right away if there is
+    // exactly one, instead of showing the menu.
+    #[serde(default)]
+    pub auto_single: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignatureHelpCycleParams {
+    pub position: KakounePosition,
+    // 1 to show the next signature, -1 for the previous one.
+    pub step: i32,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TextDocumentRenameParams {
@@ -225,6 +321,34 @@ impl Display for KakouneRange {
     }
 }
 
+impl FromStr for KakounePosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid position: {}", s);
+        let (line, column) = s.split_once('.').ok_or_else(err)?;
+        Ok(KakounePosition {
+            line: line.parse().map_err(|_| err())?,
+            column: column.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+/// Parse a Kakoune selection description, like `%val{selection_desc}`.
+impl FromStr for KakouneRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once(',')
+            .ok_or_else(|| format!("Invalid range: {}", s))?;
+        Ok(KakouneRange {
+            start: start.parse()?,
+            end: end.parse()?,
+        })
+    }
+}
+
 /// Represents how language server interprets LSP's `Position.character`
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum OffsetEncoding {
@@ -1,6 +1,7 @@
 use crate::context::*;
 use crate::language_features::rust_analyzer;
 use crate::settings::*;
+use crate::text_edit::apply_text_edits_to_file;
 use crate::types::*;
 use crate::util::*;
 use jsonrpc_core::Params;
@@ -11,6 +12,7 @@ use serde::Deserialize;
 use serde_json::{self, Value};
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 
 pub fn did_change_configuration(meta: EditorMeta, mut params: EditorParams, ctx: &mut Context) {
     let mut default_settings = toml::value::Table::new();
@@ -125,6 +127,15 @@ pub fn execute_command(meta: EditorMeta, params: EditorParams, ctx: &mut Context
         arguments: serde_json::from_str(&params.arguments).unwrap(),
         work_done_progress_params: Default::default(),
     };
+    execute_command_request(meta, req_params, ctx);
+}
+
+/// Execute a command on the server, or locally for the commands we implement ourselves.
+pub fn execute_command_request(
+    meta: EditorMeta,
+    req_params: ExecuteCommandParams,
+    ctx: &mut Context,
+) {
     match &*req_params.command {
         "rust-analyzer.applySourceChange" => {
             rust_analyzer::apply_source_change(meta, req_params, ctx);
@@ -142,7 +153,7 @@ pub fn apply_document_resource_op(
 ) -> io::Result<()> {
     match op {
         ResourceOp::Create(op) => {
-            let path = op.uri.to_file_path().unwrap();
+            let path = file_path(&op.uri)?;
             let ignore_if_exists = if let Some(options) = op.options {
                 !options.overwrite.unwrap_or(false) && options.ignore_if_exists.unwrap_or(false)
             } else {
@@ -155,7 +166,7 @@ pub fn apply_document_resource_op(
             }
         }
         ResourceOp::Delete(op) => {
-            let path = op.uri.to_file_path().unwrap();
+            let path = file_path(&op.uri)?;
             if path.is_dir() {
                 let recursive = if let Some(options) = op.options {
                     options.recursive.unwrap_or(false)
@@ -174,8 +185,8 @@ pub fn apply_document_resource_op(
             }
         }
         ResourceOp::Rename(op) => {
-            let from = op.old_uri.to_file_path().unwrap();
-            let to = op.new_uri.to_file_path().unwrap();
+            let from = file_path(&op.old_uri)?;
+            let to = file_path(&op.new_uri)?;
             let ignore_if_exists = if let Some(options) = op.options {
                 !options.overwrite.unwrap_or(false) && options.ignore_if_exists.unwrap_or(false)
             } else {
@@ -190,48 +201,199 @@ pub fn apply_document_resource_op(
     }
 }
 
-// TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
+fn file_path(uri: &Url) -> io::Result<PathBuf> {
+    uri.to_file_path().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a file URI", uri),
+        )
+    })
+}
+
+/// On-disk state of the paths touched by a workspace edit, captured before they are modified so
+/// that a partially applied edit can be rolled back.
+#[derive(Default)]
+struct Snapshot {
+    entries: Vec<(PathBuf, SnapshotEntry)>,
+}
+
+enum SnapshotEntry {
+    Missing,
+    Dir,
+    File(Vec<u8>, fs::Permissions),
+}
+
+impl Snapshot {
+    fn capture(&mut self, path: &Path) -> io::Result<()> {
+        if self.entries.iter().any(|(p, _)| p == path) {
+            return Ok(());
+        }
+        if path.is_dir() {
+            self.entries.push((path.to_path_buf(), SnapshotEntry::Dir));
+            for entry in fs::read_dir(path)? {
+                self.capture(&entry?.path())?;
+            }
+        } else if path.exists() {
+            let entry = SnapshotEntry::File(fs::read(path)?, fs::metadata(path)?.permissions());
+            self.entries.push((path.to_path_buf(), entry));
+        } else {
+            self.entries
+                .push((path.to_path_buf(), SnapshotEntry::Missing));
+        }
+        Ok(())
+    }
+
+    fn capture_resource_op(&mut self, op: &ResourceOp) -> io::Result<()> {
+        match op {
+            ResourceOp::Create(op) => self.capture(&file_path(&op.uri)?),
+            ResourceOp::Delete(op) => self.capture(&file_path(&op.uri)?),
+            ResourceOp::Rename(op) => {
+                self.capture(&file_path(&op.old_uri)?)?;
+                self.capture(&file_path(&op.new_uri)?)
+            }
+        }
+    }
+
+    /// Restore all captured paths. Paths that didn't exist are removed first, then directories
+    /// and files are recreated in capture order, so parents come before their children.
+    fn restore(self) -> io::Result<()> {
+        for (path, entry) in self.entries.iter().rev() {
+            if let SnapshotEntry::Missing = entry {
+                if path.is_dir() {
+                    fs::remove_dir_all(path)?;
+                } else if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        for (path, entry) in self.entries {
+            match entry {
+                SnapshotEntry::Missing => (),
+                SnapshotEntry::Dir => fs::create_dir_all(&path)?,
+                SnapshotEntry::File(contents, permissions) => {
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    }
+                    fs::write(&path, contents)?;
+                    fs::set_permissions(&path, permissions)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single change of a workspace edit, in the order it should be applied.
+pub enum WorkspaceChange {
+    /// Text edits to a document, and the document version they were computed against if known.
+    Edit(Url, Option<i32>, Vec<OneOf<TextEdit, AnnotatedTextEdit>>),
+    Op(ResourceOp),
+}
+
+pub fn workspace_changes(edit: WorkspaceEdit) -> Vec<WorkspaceChange> {
+    if let Some(document_changes) = edit.document_changes {
+        match document_changes {
+            DocumentChanges::Edits(edits) => edits
+                .into_iter()
+                .map(|edit| {
+                    let OptionalVersionedTextDocumentIdentifier { uri, version } =
+                        edit.text_document;
+                    WorkspaceChange::Edit(uri, version, edit.edits)
+                })
+                .collect(),
+            DocumentChanges::Operations(ops) => ops
+                .into_iter()
+                .map(|op| match op {
+                    DocumentChangeOperation::Edit(edit) => {
+                        let OptionalVersionedTextDocumentIdentifier { uri, version } =
+                            edit.text_document;
+                        WorkspaceChange::Edit(uri, version, edit.edits)
+                    }
+                    DocumentChangeOperation::Op(op) => WorkspaceChange::Op(op),
+                })
+                .collect(),
+        }
+    } else if let Some(changes) = edit.changes {
+        changes
+            .into_iter()
+            .map(|(uri, edits)| {
+                WorkspaceChange::Edit(uri, None, edits.into_iter().map(OneOf::Left).collect())
+            })
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Version of the document if it is open in the editor.
+fn editor_document_version(uri: &Url, ctx: &Context) -> Option<i32> {
+    uri.to_file_path()
+        .ok()
+        .and_then(|path| path.to_str().and_then(|buffile| ctx.documents.get(buffile)))
+        .map(|document| document.version)
+}
+
+/// Apply a workspace edit as a transaction.
+///
+/// Changes to files on disk are applied first, after snapshotting every path they touch. If any
+/// of them fails, the snapshot is restored and nothing is sent to the editor. Edits to buffers
+/// open in the editor can't be undone, so they are only sent once everything else succeeded.
+/// Edits computed against another version of a buffer than the one we have are rejected, as
+/// applying them would garble the buffer.
 pub fn apply_edit(
     meta: EditorMeta,
     edit: WorkspaceEdit,
     ctx: &mut Context,
 ) -> ApplyWorkspaceEditResponse {
-    if let Some(document_changes) = edit.document_changes {
-        match document_changes {
-            DocumentChanges::Edits(edits) => {
-                for edit in edits {
-                    apply_annotated_text_edits(&meta, &edit.text_document.uri, &edit.edits, ctx);
-                }
-            }
-            DocumentChanges::Operations(ops) => {
-                for op in ops {
-                    match op {
-                        DocumentChangeOperation::Edit(edit) => {
-                            apply_annotated_text_edits(
-                                &meta,
-                                &edit.text_document.uri,
-                                &edit.edits,
-                                ctx,
-                            );
-                        }
-                        DocumentChangeOperation::Op(op) => {
-                            if let Err(e) = apply_document_resource_op(&meta, op, ctx) {
-                                error!("failed to apply document change operation: {}", e);
-                                return ApplyWorkspaceEditResponse {
-                                    applied: false,
-                                    failure_reason: None,
-                                    failed_change: None,
-                                };
-                            }
-                        }
+    let mut snapshot = Snapshot::default();
+    let mut buffer_edits = Vec::new();
+    let mut failure = None;
+
+    for (i, change) in workspace_changes(edit).into_iter().enumerate() {
+        let result = match change {
+            WorkspaceChange::Edit(uri, version, edits) => {
+                match (editor_document_version(&uri, ctx), version) {
+                    (Some(current), Some(version)) if current != version => Err(format!(
+                        "{} was modified (edit is for version {}, buffer is at version {})",
+                        uri, version, current
+                    )),
+                    (Some(_), _) => {
+                        buffer_edits.push((uri, edits));
+                        Ok(())
                     }
+                    (None, _) => file_path(&uri)
+                        .and_then(|path| snapshot.capture(&path))
+                        .and_then(|_| apply_text_edits_to_file(&uri, &edits, ctx.offset_encoding))
+                        .map_err(|e| e.to_string()),
                 }
             }
+            WorkspaceChange::Op(op) => snapshot
+                .capture_resource_op(&op)
+                .and_then(|_| apply_document_resource_op(&meta, op, ctx))
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
+            failure = Some((i, e));
+            break;
         }
-    } else if let Some(changes) = edit.changes {
-        for (uri, change) in changes {
-            apply_text_edits(&meta, &uri, change, ctx);
+    }
+
+    if let Some((i, e)) = failure {
+        error!("Failed to apply workspace edit change {}: {}", i, e);
+        if let Err(e) = snapshot.restore() {
+            error!("Failed to roll back workspace edit: {}", e);
         }
+        let msg = format!("failed to apply workspace edit: {}", e);
+        ctx.exec(meta, format!("lsp-show-error {}", editor_quote(&msg)));
+        return ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(e),
+            failed_change: Some(i as u32),
+        };
+    }
+
+    for (uri, edits) in buffer_edits {
+        apply_annotated_text_edits(&meta, &uri, &edits, ctx);
     }
     ApplyWorkspaceEditResponse {
         applied: true,
@@ -262,3 +424,64 @@ pub fn apply_edit_from_server(
     let response = apply_edit(meta, params.edit, ctx);
     Ok(serde_json::to_value(response).unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_edit_rolls_back_on_failure() {
+        let root = std::env::temp_dir().join(format!("kak-lsp-workspace-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let modified = root.join("modified.txt");
+        let created = root.join("created.txt");
+        let missing = root.join("missing.txt");
+        fs::write(&modified, "old\n").unwrap();
+        let url = |path: &Path| Url::from_file_path(path).unwrap();
+        let edit = |path: &Path| {
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    uri: url(path),
+                    version: None,
+                },
+                edits: vec![OneOf::Left(TextEdit {
+                    range: Range::new(Position::new(0, 0), Position::new(0, 3)),
+                    new_text: "new".to_string(),
+                })],
+            })
+        };
+        let workspace_edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: url(&created),
+                    options: None,
+                    annotation_id: None,
+                })),
+                edit(&modified),
+                edit(&missing),
+            ])),
+            ..WorkspaceEdit::default()
+        };
+
+        let (mut ctx, _editor_rx) = test_context(root.to_str().unwrap());
+        let response = apply_edit(test_meta(), workspace_edit, &mut ctx);
+        let modified_contents = fs::read_to_string(&modified).unwrap();
+        let created_exists = created.exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(!response.applied);
+        assert_eq!(response.failed_change, Some(2));
+        assert!(!created_exists);
+        assert_eq!(modified_contents, "old\n");
+    }
+
+    #[test]
+    fn snapshot_rejects_non_file_uris() {
+        let op = ResourceOp::Create(CreateFile {
+            uri: Url::parse("untitled:Untitled-1").unwrap(),
+            options: None,
+            annotation_id: None,
+        });
+        assert!(Snapshot::default().capture_resource_op(&op).is_err());
+    }
+}